@@ -0,0 +1,200 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+// One `allow_from` entry: an address with a prefix length ("10.0.0.0/8",
+// "192.168.1.20", "fd00::/8").
+#[derive(Debug, Clone, Copy)]
+struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    fn parse(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|e| format!("Invalid allow_from entry '{}': {}", s, e))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("Invalid prefix length in allow_from entry '{}'", s))?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// Client addresses allowed to use a rule; empty means everyone.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct AllowList {
+    entries: Vec<Cidr>,
+}
+
+impl AllowList {
+    pub fn parse(entries: &[String]) -> Result<Self, String> {
+        Ok(Self {
+            entries: entries.iter().map(|e| Cidr::parse(e)).collect::<Result<_, _>>()?,
+        })
+    }
+
+    // Only this machine, like a listener on a loopback address.
+    pub fn loopback() -> Self {
+        Self {
+            entries: vec![
+                Cidr {
+                    addr: Ipv4Addr::new(127, 0, 0, 0).into(),
+                    prefix: 8,
+                },
+                Cidr {
+                    addr: Ipv6Addr::LOCALHOST.into(),
+                    prefix: 128,
+                },
+            ],
+        }
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        // Dual-stack listeners report IPv4 clients as ::ffff:a.b.c.d.
+        let ip = ip.to_canonical();
+        self.entries.is_empty() || self.entries.iter().any(|c| c.contains(ip))
+    }
+}
+
+// "*.example.com" matches any name below example.com; everything else is exact.
+// Comparison is case-insensitive.
+pub(crate) fn host_matches(pattern: &str, hostname: &str) -> bool {
+    let hostname = hostname.trim_end_matches('.').to_ascii_lowercase();
+    let pattern = pattern.to_ascii_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => hostname
+            .strip_suffix(suffix)
+            .is_some_and(|rest| rest.len() > 1 && rest.ends_with('.')),
+        None => hostname == pattern,
+    }
+}
+
+// Destination hosts a rule may connect to: `deny` wins, and a non-empty `allow`
+// admits only the hosts it matches.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct HostFilter {
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+impl HostFilter {
+    pub fn new(allow: &[String], deny: &[String]) -> Self {
+        Self {
+            allow: allow.to_vec(),
+            deny: deny.to_vec(),
+        }
+    }
+
+    pub fn permits(&self, host: &str) -> bool {
+        // "[::1]" in a URL is "::1" in the patterns.
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if self.deny.iter().any(|p| host_matches(p, host)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|p| host_matches(p, host))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_parse() {
+        let c = Cidr::parse(" 10.0.0.0/8 ").unwrap();
+        assert_eq!((c.addr, c.prefix), (ip("10.0.0.0"), 8));
+        assert_eq!(Cidr::parse("192.168.1.20").unwrap().prefix, 32);
+        assert_eq!(Cidr::parse("fd00::/8").unwrap().prefix, 8);
+        assert_eq!(Cidr::parse("::1").unwrap().prefix, 128);
+        for bad in ["10.0.0.0/33", "fd00::/129", "10.0.0.0/", "10.0.0.0/x", "10.0.0/8", "example.com", ""] {
+            assert!(Cidr::parse(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn cidr_contains() {
+        let net = Cidr::parse("192.168.1.0/24").unwrap();
+        assert!(net.contains(ip("192.168.1.0")));
+        assert!(net.contains(ip("192.168.1.255")));
+        assert!(!net.contains(ip("192.168.2.1")));
+        // Families never match each other.
+        assert!(!net.contains(ip("::ffff:192.168.1.1")));
+
+        let host = Cidr::parse("10.1.2.3").unwrap();
+        assert!(host.contains(ip("10.1.2.3")));
+        assert!(!host.contains(ip("10.1.2.4")));
+
+        let any = Cidr::parse("0.0.0.0/0").unwrap();
+        assert!(any.contains(ip("203.0.113.7")));
+
+        let v6 = Cidr::parse("fd00::/8").unwrap();
+        assert!(v6.contains(ip("fd12:3456::1")));
+        assert!(!v6.contains(ip("fe80::1")));
+        assert!(Cidr::parse("::/0").unwrap().contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn allow_list() {
+        let entries = ["10.0.0.0/8".to_string(), "fd00::/8".to_string()];
+        let allow = AllowList::parse(&entries).unwrap();
+        assert!(allow.allows(ip("10.9.8.7")));
+        // IPv4 clients of a dual-stack listener.
+        assert!(allow.allows(ip("::ffff:10.9.8.7")));
+        assert!(allow.allows(ip("fd00::5")));
+        assert!(!allow.allows(ip("192.168.1.1")));
+        assert!(AllowList::parse(&[]).unwrap().allows(ip("192.168.1.1")));
+        assert!(AllowList::parse(&["10.0.0.0/99".to_string()]).is_err());
+    }
+
+    #[test]
+    fn host_patterns() {
+        assert!(host_matches("db.internal", "db.internal"));
+        assert!(host_matches("db.internal", "DB.Internal."));
+        assert!(!host_matches("db.internal", "x.db.internal"));
+
+        assert!(host_matches("*.example.com", "a.example.com"));
+        assert!(host_matches("*.Example.com", "a.b.EXAMPLE.com"));
+        assert!(!host_matches("*.example.com", "example.com"));
+        assert!(!host_matches("*.example.com", ".example.com"));
+        assert!(!host_matches("*.example.com", "badexample.com"));
+    }
+
+    #[test]
+    fn host_filter() {
+        let filter = HostFilter::new(&["*.corp.internal".to_string(), "::1".to_string()], &["secret.corp.internal".to_string()]);
+        assert!(filter.permits("wiki.corp.internal"));
+        assert!(filter.permits("[::1]"));
+        assert!(!filter.permits("secret.corp.internal"));
+        assert!(!filter.permits("example.com"));
+        assert!(HostFilter::new(&[], &[]).permits("example.com"));
+    }
+}
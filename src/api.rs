@@ -0,0 +1,220 @@
+use std::io;
+use std::sync::Arc;
+
+use serde::Serialize;
+use tokio::sync::watch;
+use tracing::info;
+
+use crate::config::ApiConfig;
+use crate::http::{self, Request, Response};
+use crate::status::{Command, StatusBoard};
+
+#[derive(Serialize)]
+struct Accepted<'a> {
+    rule: &'a str,
+    command: Command,
+}
+
+#[derive(Serialize)]
+struct Logs<'a> {
+    rule: &'a str,
+    lines: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ApiError<'a> {
+    error: &'a str,
+}
+
+// Serve the status API on `[api] listen`:
+//   GET  /rules                          status of every rule
+//   GET  /rules/{name}                   status of one rule
+//   GET  /rules/{name}/logs              recent ssh output of one rule
+//   POST /rules/{name}/start|stop|restart
+// Every request needs `Authorization: Bearer <token>`.
+pub(crate) async fn run(config: ApiConfig, board: Arc<StatusBoard>, shutdown: watch::Receiver<bool>) -> io::Result<()> {
+    if config.token.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "[api] token must not be empty"));
+    }
+    let listener = http::bind(&config.listen, "api").await?;
+    info!("Serving status API on http://{}/rules", config.listen);
+    http::serve(listener, move |request: &Request| handle(request, &config.token, &board), shutdown).await
+}
+
+fn handle(request: &Request, token: &str, board: &StatusBoard) -> Response {
+    if !authorized(request, token) {
+        return error("401 Unauthorized", "missing or wrong bearer token")
+            .with_header("WWW-Authenticate", "Bearer".to_string());
+    }
+    // "/rules" or "/rules/...", not "/rulesXYZ".
+    let rest = match request.path.strip_prefix("/rules") {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => rest,
+        _ => return error("404 Not Found", "not found"),
+    };
+    let segments: Vec<String> = rest.split('/').skip(1).map(percent_decode).collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET" | "HEAD", []) => Response::json("200 OK", &board.report()),
+        ("GET" | "HEAD", [name]) => match board.find(name) {
+            Some(rule) => Response::json("200 OK", &rule.snapshot()),
+            None => unknown_rule(),
+        },
+        ("GET" | "HEAD", [name, logs]) if logs == "logs" => match board.find(name) {
+            Some(rule) => Response::json(
+                "200 OK",
+                &Logs {
+                    rule: name,
+                    lines: rule.output.lines(),
+                },
+            ),
+            None => unknown_rule(),
+        },
+        ("POST", [name, action]) => {
+            let command = match action.as_str() {
+                "start" => Command::Start,
+                "stop" => Command::Stop,
+                "restart" => Command::Restart,
+                "logs" => return error("405 Method Not Allowed", "method not allowed"),
+                _ => return error("404 Not Found", "unknown action; use start, stop or restart"),
+            };
+            let Some(rule) = board.find(name) else {
+                return unknown_rule();
+            };
+            match rule.command(command) {
+                Ok(()) => Response::json("202 Accepted", &Accepted { rule: name, command }),
+                Err(e) => error("409 Conflict", &e),
+            }
+        }
+        (_, [] | [_] | [_, _]) => error("405 Method Not Allowed", "method not allowed"),
+        _ => error("404 Not Found", "not found"),
+    }
+}
+
+fn authorized(request: &Request, token: &str) -> bool {
+    let Some(given) = request.header("Authorization").and_then(|v| v.strip_prefix("Bearer ")) else {
+        return false;
+    };
+    // Compare without stopping at the first difference.
+    given.len() == token.len() && given.bytes().zip(token.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn error(status: &'static str, message: &str) -> Response {
+    Response::json(status, &ApiError { error: message })
+}
+
+fn unknown_rule() -> Response {
+    error("404 Not Found", "no such rule")
+}
+
+// Rule names like "127.0.0.1:5432" go into the path as-is; anything else may be %-encoded.
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        // Two hex digits; from_str_radix alone would also take a sign ("%+1").
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .filter(|h| h.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|h| std::str::from_utf8(h).ok());
+        match (bytes[i], hex.and_then(|h| u8::from_str_radix(h, 16).ok())) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::RuleState;
+    use tokio::sync::broadcast;
+
+    const TOKEN: &str = "s3cret";
+
+    fn board() -> StatusBoard {
+        let board = StatusBoard::new(broadcast::channel(16).0);
+        board.register("db".to_string(), String::new(), false);
+        board.register("127.0.0.1:5432".to_string(), String::new(), false);
+        board
+    }
+
+    fn request(board: &StatusBoard, method: &str, path: &str) -> Response {
+        let auth = format!("Bearer {}", TOKEN);
+        handle(&Request::new(method, path, &[("Authorization", &auth)]), TOKEN, board)
+    }
+
+    #[test]
+    fn bearer_token_is_required() {
+        let board = board();
+        let with = |headers: &[(&str, &str)]| handle(&Request::new("GET", "/rules", headers), TOKEN, &board).status;
+        assert_eq!(with(&[]), "401 Unauthorized");
+        assert_eq!(with(&[("Authorization", "Bearer wrong!")]), "401 Unauthorized");
+        assert_eq!(with(&[("Authorization", "Bearer s3cre")]), "401 Unauthorized");
+        assert_eq!(with(&[("Authorization", "Bearer s3cret2")]), "401 Unauthorized");
+        assert_eq!(with(&[("Authorization", "Basic czNjcmV0")]), "401 Unauthorized");
+        assert_eq!(with(&[("Authorization", "s3cret")]), "401 Unauthorized");
+        assert_eq!(with(&[("authorization", "Bearer s3cret")]), "200 OK");
+    }
+
+    #[test]
+    fn paths() {
+        let board = board();
+        let status = |method: &str, path: &str| request(&board, method, path).status;
+        assert_eq!(status("GET", "/rules"), "200 OK");
+        assert_eq!(status("GET", "/rules/db"), "200 OK");
+        assert_eq!(status("GET", "/rules/db/logs"), "200 OK");
+        assert_eq!(status("GET", "/rules/127.0.0.1:5432"), "200 OK");
+        assert_eq!(status("GET", "/rules/127.0.0.1%3A5432"), "200 OK");
+        // Only "/rules" itself and what is below it.
+        assert_eq!(status("GET", "/rulesXYZ"), "404 Not Found");
+        assert_eq!(status("GET", "/rules.json"), "404 Not Found");
+        assert_eq!(status("GET", "/rule"), "404 Not Found");
+        assert_eq!(status("GET", "/"), "404 Not Found");
+        assert_eq!(status("GET", "/rules/"), "404 Not Found");
+        assert_eq!(status("GET", "/rules/nope"), "404 Not Found");
+        assert_eq!(status("GET", "/rules/db/logs/x"), "404 Not Found");
+        assert_eq!(status("GET", "/rules/db/stop"), "405 Method Not Allowed");
+        assert_eq!(status("POST", "/rules/db/logs"), "405 Method Not Allowed");
+        assert_eq!(status("POST", "/rules/db/pause"), "404 Not Found");
+        assert_eq!(status("DELETE", "/rules/db"), "405 Method Not Allowed");
+    }
+
+    #[test]
+    fn commands_on_an_auth_failed_rule_conflict() {
+        let board = board();
+        let response = request(&board, "POST", "/rules/db/restart");
+        assert_eq!(response.status, "202 Accepted");
+        assert!(response.body.contains("\"command\": \"restart\""), "{}", response.body);
+
+        board.find("db").unwrap().set_state(RuleState::AuthFailed);
+        for action in ["start", "stop", "restart"] {
+            let response = request(&board, "POST", &format!("/rules/db/{}", action));
+            assert_eq!(response.status, "409 Conflict");
+            assert!(response.body.contains("authentication failure"), "{}", response.body);
+        }
+        // Other rules still take commands.
+        assert_eq!(request(&board, "POST", "/rules/127.0.0.1:5432/stop").status, "202 Accepted");
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("db"), "db");
+        assert_eq!(percent_decode("127.0.0.1%3A5432"), "127.0.0.1:5432");
+        assert_eq!(percent_decode("my%20rule"), "my rule");
+        assert_eq!(percent_decode("%e2%9C%93"), "\u{2713}");
+        // Not an escape: kept as is.
+        assert_eq!(percent_decode("%zz"), "%zz");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%2"), "%2");
+        assert_eq!(percent_decode("%+1"), "%+1");
+        // Bytes that are not UTF-8 are replaced.
+        assert_eq!(percent_decode("%ff"), "\u{fffd}");
+    }
+}
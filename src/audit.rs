@@ -0,0 +1,302 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tracing::error;
+
+use crate::config::AuditConfig;
+use crate::paths::state_dir;
+
+/// One line of the audit log: a client connection to an audited rule.
+#[derive(Serialize, Debug)]
+pub(crate) struct AuditEntry<'a> {
+    pub rule: &'a str,
+    pub client: String,
+    /// host:port requested through an `http_proxy` rule.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<&'a str>,
+    /// RFC 3339, UTC.
+    pub start: String,
+    pub end: String,
+    pub duration_ms: u64,
+    /// Client -> remote.
+    pub bytes_up: u64,
+    /// Remote -> client.
+    pub bytes_down: u64,
+    /// client_closed, remote_closed, error: ..., tunnel_not_ready,
+    /// rejected: allow_from, rejected: max_connections, rejected: host,
+    /// bad_request: ..., shutdown
+    pub close_reason: &'a str,
+}
+
+struct LogFile {
+    file: File,
+    size: u64,
+}
+
+// Append-only JSONL file in the state directory, rotated by size:
+// audit.jsonl -> audit.jsonl.1 -> ... -> audit.jsonl.<keep>.
+//
+// Entries are recorded from connection drops on the runtime's worker threads,
+// so the file writes and renames happen on a thread of their own. `close`
+// waits for the entries already queued; dropping the log only detaches the
+// thread, which finishes them on its own.
+pub(crate) struct AuditLog {
+    path: PathBuf,
+    lines: Mutex<Option<mpsc::Sender<Vec<u8>>>>,
+    writer: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+impl AuditLog {
+    pub fn open(config: &AuditConfig) -> io::Result<Self> {
+        Self::start(Writer {
+            path: state_dir()?.join("audit.jsonl"),
+            max_size: config.max_size,
+            keep: config.keep,
+            file: None,
+        })
+    }
+
+    fn start(mut writer: Writer) -> io::Result<Self> {
+        let path = writer.path.clone();
+        // Fail here, not on the first connection, if the file cannot be opened.
+        writer.file = Some(writer.open_file()?);
+        let (tx, rx) = mpsc::channel::<Vec<u8>>();
+        let handle = thread::Builder::new().name("audit-log".to_string()).spawn(move || {
+            for line in rx {
+                if let Err(e) = writer.append(&line) {
+                    error!("audit log {}: {}", writer.path.display(), e);
+                }
+            }
+        })?;
+        Ok(Self {
+            path,
+            lines: Mutex::new(Some(tx)),
+            writer: Mutex::new(Some(handle)),
+        })
+    }
+
+    /// Stop taking entries and wait until the queued ones are written.
+    pub async fn close(&self) {
+        // Closing the channel ends the writer once it has written the rest.
+        self.lines.lock().unwrap().take();
+        let writer = self.writer.lock().unwrap().take();
+        if let Some(writer) = writer {
+            let _ = tokio::task::spawn_blocking(move || writer.join()).await;
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn record(&self, entry: &AuditEntry) {
+        let mut line = match serde_json::to_vec(entry) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("audit log: {}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+        if let Some(lines) = &*self.lines.lock().unwrap() {
+            let _ = lines.send(line);
+        }
+    }
+}
+
+// The audit log's file, owned by its writer thread.
+struct Writer {
+    path: PathBuf,
+    max_size: u64,
+    keep: u32,
+    file: Option<LogFile>,
+}
+
+impl Writer {
+    fn append(&mut self, line: &[u8]) -> io::Result<()> {
+        let full = self
+            .file
+            .as_ref()
+            .is_some_and(|f| f.size > 0 && f.size + line.len() as u64 > self.max_size);
+        if full || self.file.is_none() {
+            self.file = None;
+            if full {
+                self.rotate()?;
+            }
+            self.file = Some(self.open_file()?);
+        }
+        let log = self.file.as_mut().expect("audit log file is open");
+        log.file.write_all(line)?;
+        log.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&self) -> io::Result<()> {
+        if self.keep == 0 {
+            return fs::remove_file(&self.path);
+        }
+        for n in (1..self.keep).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                fs::rename(&from, self.rotated(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated(1))
+    }
+
+    fn rotated(&self, n: u32) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn open_file(&self) -> io::Result<LogFile> {
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let file = options.open(&self.path)?;
+        let size = file.metadata()?.len();
+        Ok(LogFile { file, size })
+    }
+}
+
+// RFC 3339 timestamp in UTC with millisecond precision.
+pub(crate) fn format_timestamp(t: SystemTime) -> String {
+    let d = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = d.as_secs();
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm).
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        d.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // A fresh directory per test; kept for a look if the test fails.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ssh-tunnel-manager-audit-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn writer(dir: &Path, max_size: u64, keep: u32) -> Writer {
+        Writer {
+            path: dir.join("audit.jsonl"),
+            max_size,
+            keep,
+            file: None,
+        }
+    }
+
+    fn read(path: PathBuf) -> String {
+        fs::read_to_string(path).unwrap_or_default()
+    }
+
+    #[test]
+    fn rotates_when_the_size_limit_is_crossed() {
+        let dir = scratch("rotates");
+        let mut writer = writer(&dir, 10, 2);
+        for line in ["aaaa\n", "bbbb\n", "cccc\n", "dddd\n", "eeee\n", "ffff\n", "gggg\n"] {
+            writer.append(line.as_bytes()).unwrap();
+        }
+        assert_eq!(read(writer.path.clone()), "gggg\n");
+        assert_eq!(read(writer.rotated(1)), "eeee\nffff\n");
+        assert_eq!(read(writer.rotated(2)), "cccc\ndddd\n");
+        // aaaa and bbbb went with the third file.
+        assert!(!writer.rotated(3).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn keep_zero_starts_over() {
+        let dir = scratch("keep_zero");
+        let mut writer = writer(&dir, 10, 0);
+        for line in ["aaaa\n", "bbbb\n", "cccc\n"] {
+            writer.append(line.as_bytes()).unwrap();
+        }
+        assert_eq!(read(writer.path.clone()), "cccc\n");
+        assert!(!writer.rotated(1).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn oversized_lines_still_go_to_a_file_of_their_own() {
+        let dir = scratch("oversized");
+        let mut writer = writer(&dir, 4, 1);
+        writer.append(b"0123456789\n").unwrap();
+        writer.append(b"x\n").unwrap();
+        assert_eq!(read(writer.path.clone()), "x\n");
+        assert_eq!(read(writer.rotated(1)), "0123456789\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn close_waits_for_queued_entries() {
+        let dir = scratch("close");
+        let log = AuditLog::start(writer(&dir, 1 << 20, 1)).unwrap();
+        for n in 0..100 {
+            log.record(&AuditEntry {
+                rule: "db",
+                client: format!("127.0.0.1:{}", 40000 + n),
+                target: None,
+                start: String::new(),
+                end: String::new(),
+                duration_ms: 0,
+                bytes_up: 0,
+                bytes_down: 0,
+                close_reason: "client_closed",
+            });
+        }
+        log.close().await;
+        assert_eq!(read(log.path().to_path_buf()).lines().count(), 100);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn timestamps() {
+        let at = |secs: u64, millis: u64| {
+            format_timestamp(UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_millis(millis))
+        };
+        assert_eq!(at(0, 0), "1970-01-01T00:00:00.000Z");
+        // Leap days, including the 400-year rule.
+        assert_eq!(at(951_782_400, 0), "2000-02-29T00:00:00.000Z");
+        assert_eq!(at(1_709_210_096, 500), "2024-02-29T12:34:56.500Z");
+        assert_eq!(at(1_709_251_199, 999), "2024-02-29T23:59:59.999Z");
+        assert_eq!(at(1_709_251_200, 0), "2024-03-01T00:00:00.000Z");
+        // 2100 is not a leap year.
+        assert_eq!(at(4_107_542_399, 999), "2100-02-28T23:59:59.999Z");
+        assert_eq!(at(4_107_542_400, 0), "2100-03-01T00:00:00.000Z");
+        // Before the epoch clamps to it.
+        assert_eq!(format_timestamp(UNIX_EPOCH - Duration::from_secs(1)), "1970-01-01T00:00:00.000Z");
+    }
+}
@@ -1,11 +1,103 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::{fs, io};
 
+/// Which side listens for connections.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ForwardKind {
+    /// `ssh -L`: listen on `local_bind:local_port`, connect to `remote_address` from the SSH server.
+    #[default]
+    Local,
+    /// `ssh -R`: the SSH server listens on `remote_address`, connections go to `local_bind:local_port`.
+    Remote,
+    /// `ssh -D`: SOCKS proxy on `local_bind:local_port`; the SSH server connects to
+    /// whatever each client asks for (`remote_address` is not used).
+    Dynamic,
+}
+
+/// How the SSH connection is made.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// The system `ssh` binary.
+    #[default]
+    Ssh,
+    /// Built-in SSH client (requires the `native-ssh` cargo feature).
+    Native,
+}
+
+/// When the SSH connection runs.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// Connected all the time, reconnecting when it drops.
+    #[default]
+    Persistent,
+    /// The manager listens on `local_bind:local_port` and only connects while
+    /// there are client connections (plus `idle_timeout`).
+    OnDemand,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct ForwardingRule {
+    // Name used in status output (optional; see `ForwardingRule::name`)
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub kind: ForwardKind,
+    #[serde(default)]
+    pub backend: Backend,
+    #[serde(default)]
+    pub mode: Mode,
+    // Seconds without client connections before an on-demand tunnel is stopped
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: u64,
+    // Manager-owned listener in front of the ssh forward (implied by on_demand)
+    #[serde(default)]
+    pub front_proxy: bool,
+    // Seconds a client connection waits for the tunnel to be (re)connected
+    #[serde(default = "default_queue_timeout")]
+    pub queue_timeout: u64,
+    // Client addresses / CIDRs allowed to connect (implies front_proxy)
+    #[serde(default)]
+    pub allow_from: Vec<String>,
+    // Limits enforced by the front proxy (each implies front_proxy)
+    #[serde(default)]
+    pub max_connections: Option<u64>,
+    // Bytes/sec, client -> remote and remote -> client, shared by all connections of the rule
+    #[serde(default)]
+    pub rate_limit_up: Option<u64>,
+    #[serde(default)]
+    pub rate_limit_down: Option<u64>,
+    // Record every client connection in the audit log (implies front_proxy)
+    #[serde(default)]
+    pub audit_log: bool,
+    // Serve an HTTP proxy (CONNECT and absolute-URI requests) on local_bind:local_port,
+    // relaying through the rule's SOCKS tunnel (requires kind = "dynamic"; implies front_proxy)
+    #[serde(default)]
+    pub http_proxy: bool,
+    // Hosts the HTTP proxy may / may not connect to ("example.com", "*.corp.internal")
+    #[serde(default)]
+    pub allow_hosts: Vec<String>,
+    #[serde(default)]
+    pub deny_hosts: Vec<String>,
+    // Domains ("example.com", "*.corp.example.com") the [pac] script sends through this proxy rule
+    #[serde(default)]
+    pub pac_domains: Vec<String>,
+    // Hostnames ("app.internal", "*.apps.internal") the [router] sends to this rule
+    #[serde(default)]
+    pub hostnames: Vec<String>,
+    // Required, except for auto_loopback local forwards (default: the remote port)
+    #[serde(default)]
     pub local_port: u16,
     #[serde(default = "default_local_bind")]
     pub local_bind: String,
+    // Bind to the next free address of the [loopback] range instead of local_bind
+    #[serde(default)]
+    pub auto_loopback: bool,
+    // Not used by dynamic forwards
+    #[serde(default)]
     pub remote_address: String,
     pub ssh_host: String,
     #[serde(default = "default_ssh_port")]
@@ -18,25 +110,377 @@ pub struct ForwardingRule {
     // Extra arguments passed through to ssh (optional)
     #[serde(default)]
     pub ssh_extra_args: Vec<String>,
+    // Share one OpenSSH ControlMaster with every rule for the same host profile
+    #[serde(default)]
+    pub control_master: bool,
+    // Shell commands run when the tunnel comes up / goes down / fails to authenticate
+    // (default: the ones in [hooks])
+    #[serde(default)]
+    pub on_up: Option<String>,
+    #[serde(default)]
+    pub on_down: Option<String>,
+    #[serde(default)]
+    pub on_auth_failure: Option<String>,
+    // Seconds before a hook command is killed (default: [hooks] timeout, else 30)
+    #[serde(default)]
+    pub hook_timeout: Option<u64>,
+    // Rules (by name) that must be connected before this one starts; it is
+    // stopped while any of them is down
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+}
+
+impl ForwardingRule {
+    /// Configured name, else the address the rule listens on
+    /// (`local_bind:local_port` for local and dynamic forwards, `remote_address` for remote ones).
+    pub fn name(&self) -> String {
+        if let Some(name) = self.name.as_deref().filter(|s| !s.is_empty()) {
+            return name.to_string();
+        }
+        match self.kind {
+            ForwardKind::Local | ForwardKind::Dynamic => format!("{}:{}", self.local_bind, self.local_port),
+            ForwardKind::Remote => self.remote_address.clone(),
+        }
+    }
+
+    /// Password used to answer interactive prompts (password / key passphrase), if configured.
+    pub fn password(&self) -> Option<&str> {
+        self.ssh_password.as_deref().filter(|s| !s.is_empty())
+    }
 }
 
 fn default_ssh_port() -> u16 {
     22
 }
 
+fn default_idle_timeout() -> u64 {
+    300
+}
+
+fn default_queue_timeout() -> u64 {
+    30
+}
+
 fn default_local_bind() -> String {
     // Default to localhost for security: listen on 127.0.0.1
     "127.0.0.1".to_string()
 }
 
-#[derive(Deserialize, Debug)]
+/// `[audit]`: rotation of the connection audit log (`audit.jsonl` in the state directory).
+#[derive(Deserialize, Debug, Clone)]
+pub struct AuditConfig {
+    /// Rotate once the file would grow beyond this many bytes.
+    #[serde(default = "default_audit_max_size")]
+    pub max_size: u64,
+    /// Number of rotated files to keep (audit.jsonl.1 ... audit.jsonl.<keep>).
+    #[serde(default = "default_audit_keep")]
+    pub keep: u32,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            max_size: default_audit_max_size(),
+            keep: default_audit_keep(),
+        }
+    }
+}
+
+fn default_audit_max_size() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_audit_keep() -> u32 {
+    5
+}
+
+/// `[loopback]`: addresses assigned to rules with `auto_loopback = true`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct LoopbackConfig {
+    /// CIDR inside 127.0.0.0/8; 127.0.0.1 is never assigned.
+    #[serde(default = "default_loopback_range")]
+    pub range: String,
+}
+
+impl Default for LoopbackConfig {
+    fn default() -> Self {
+        Self {
+            range: default_loopback_range(),
+        }
+    }
+}
+
+fn default_loopback_range() -> String {
+    "127.0.0.0/24".to_string()
+}
+
+/// `[api]`: HTTP/JSON status API.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ApiConfig {
+    /// Address to listen on.
+    #[serde(default = "default_api_listen")]
+    pub listen: String,
+    /// Clients must send `Authorization: Bearer <token>`.
+    pub token: String,
+}
+
+fn default_api_listen() -> String {
+    "127.0.0.1:9185".to_string()
+}
+
+/// `[metrics]`: Prometheus endpoint.
+#[derive(Deserialize, Debug, Clone)]
+pub struct MetricsConfig {
+    /// Address to serve `/metrics` on, e.g. `"127.0.0.1:9184"`.
+    pub listen: String,
+}
+
+/// `[router]`: shared listeners that dispatch connections to rules by TLS SNI
+/// or HTTP Host header (see `ForwardingRule::hostnames`).
+#[derive(Deserialize, Debug, Clone)]
+pub struct RouterConfig {
+    /// Addresses to listen on, e.g. `["127.0.0.1:443", "127.0.0.1:80"]`.
+    pub listen: Vec<String>,
+}
+
+/// `[pac]`: proxy auto-config script for the rules with `pac_domains`, served over HTTP.
+#[derive(Deserialize, Debug, Clone)]
+pub struct PacConfig {
+    /// Address to serve `/proxy.pac` on, e.g. `"127.0.0.1:8079"`.
+    pub listen: String,
+}
+
+/// `[hooks]`: commands for every rule that does not set its own
+/// `on_up` / `on_down` / `on_auth_failure` / `hook_timeout`.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct HooksConfig {
+    #[serde(default)]
+    pub on_up: Option<String>,
+    #[serde(default)]
+    pub on_down: Option<String>,
+    #[serde(default)]
+    pub on_auth_failure: Option<String>,
+    /// Seconds before a hook command is killed.
+    #[serde(default)]
+    pub timeout: Option<u64>,
+}
+
+impl HooksConfig {
+    /// Fill in the hooks the rule does not set itself.
+    pub fn apply(&self, rule: &mut ForwardingRule) {
+        rule.on_up = rule.on_up.take().or_else(|| self.on_up.clone());
+        rule.on_down = rule.on_down.take().or_else(|| self.on_down.clone());
+        rule.on_auth_failure = rule.on_auth_failure.take().or_else(|| self.on_auth_failure.clone());
+        rule.hook_timeout = rule.hook_timeout.or(self.timeout);
+    }
+}
+
+/// Rule state changes a `[[webhook]]` reports.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    /// Not connected for `down_after` seconds.
+    Down,
+    /// Connected again after a `down` was sent.
+    Up,
+    /// Authentication failed; the rule is not retried.
+    AuthFailed,
+}
+
+/// `[[webhook]]`: POST rule state changes as JSON to a URL.
+#[derive(Deserialize, Debug, Clone)]
+pub struct WebhookConfig {
+    /// `http://` or `https://` URL.
+    pub url: String,
+    /// Rules to report on (default: all).
+    #[serde(default)]
+    pub rules: Vec<String>,
+    /// State changes to report (default: all).
+    #[serde(default = "default_webhook_events")]
+    pub events: Vec<WebhookEvent>,
+    /// Seconds a rule must stay down before `down` is sent.
+    #[serde(default = "default_webhook_down_after")]
+    pub down_after: u64,
+    /// Attempts after a failed POST, with growing delays.
+    #[serde(default = "default_webhook_retries")]
+    pub retries: u32,
+    /// Seconds each attempt may take.
+    #[serde(default = "default_webhook_timeout")]
+    pub timeout: u64,
+    /// Request body with `{rule}`, `{event}`, `{reason}`, `{down_secs}` and
+    /// `{timestamp}` placeholders (default: a JSON object with these fields).
+    #[serde(default)]
+    pub template: Option<String>,
+    /// Extra request headers, e.g. `Authorization`.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+fn default_webhook_events() -> Vec<WebhookEvent> {
+    vec![WebhookEvent::Down, WebhookEvent::Up, WebhookEvent::AuthFailed]
+}
+
+fn default_webhook_down_after() -> u64 {
+    60
+}
+
+fn default_webhook_retries() -> u32 {
+    3
+}
+
+fn default_webhook_timeout() -> u64 {
+    10
+}
+
+#[derive(Deserialize, Debug, Default)]
 pub struct Config {
+    #[serde(default)]
+    pub audit: AuditConfig,
+    #[serde(default)]
+    pub loopback: LoopbackConfig,
+    #[serde(default)]
+    pub router: Option<RouterConfig>,
+    #[serde(default)]
+    pub pac: Option<PacConfig>,
+    #[serde(default)]
+    pub metrics: Option<MetricsConfig>,
+    #[serde(default)]
+    pub api: Option<ApiConfig>,
+    #[serde(default)]
+    pub hooks: HooksConfig,
+    #[serde(default)]
+    pub webhook: Vec<WebhookConfig>,
     pub forwarding: Vec<ForwardingRule>,
 }
 
 pub fn load_config(config_path: &str) -> io::Result<Config> {
     let config_str = fs::read_to_string(config_path)?;
-    toml::de::from_str(&config_str)
-        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    let config: Config = toml::de::from_str(&config_str)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    check_depends_on(&config.forwarding).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(config)
 }
 
+/// Every `depends_on` entry must name a persistent rule, without cycles; an
+/// `auto_loopback` rule needs an explicit `name` to be named.
+pub fn check_depends_on(rules: &[ForwardingRule]) -> Result<(), String> {
+    let names: Vec<String> = rules.iter().map(|r| r.name()).collect();
+    let mut deps = Vec::with_capacity(rules.len());
+    for (rule, name) in rules.iter().zip(&names) {
+        let mut indexes = Vec::new();
+        for dep in &rule.depends_on {
+            match names.iter().position(|n| n == dep) {
+                // Only connected while in use, so dependents would wait for a client.
+                Some(i) if rules[i].mode == Mode::OnDemand => {
+                    return Err(format!("rule {}: depends_on names an on_demand rule: {}", name, dep))
+                }
+                // Its default name changes once it is given its loopback address.
+                Some(i) if rules[i].auto_loopback && rules[i].name.as_deref().is_none_or(str::is_empty) => {
+                    return Err(format!(
+                        "rule {}: depends_on names an auto_loopback rule without a name: {} (set its name)",
+                        name, dep
+                    ))
+                }
+                Some(i) => indexes.push(i),
+                None => return Err(format!("rule {}: depends_on names no rule: {}", name, dep)),
+            }
+        }
+        deps.push(indexes);
+    }
+
+    // Depth-first; `path` is the chain being visited, to report a cycle.
+    fn visit(i: usize, deps: &[Vec<usize>], names: &[String], done: &mut [bool], path: &mut Vec<usize>) -> Result<(), String> {
+        if done[i] {
+            return Ok(());
+        }
+        if let Some(start) = path.iter().position(|&p| p == i) {
+            let cycle: Vec<&str> = path[start..].iter().chain([&i]).map(|&p| names[p].as_str()).collect();
+            return Err(format!("depends_on cycle: {}", cycle.join(" -> ")));
+        }
+        path.push(i);
+        for &dep in &deps[i] {
+            visit(dep, deps, names, done, path)?;
+        }
+        path.pop();
+        done[i] = true;
+        Ok(())
+    }
+
+    let mut done = vec![false; rules.len()];
+    for i in 0..rules.len() {
+        visit(i, &deps, &names, &mut done, &mut Vec::new())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A rule named `name` depending on `deps`.
+    fn rule(name: &str, deps: &[&str]) -> ForwardingRule {
+        let mut rule: ForwardingRule = toml::from_str(&format!(
+            r#"
+name = "{name}"
+local_port = 15432
+remote_address = "db.internal:5432"
+ssh_host = "bastion.example.com"
+ssh_user = "tester"
+"#
+        ))
+        .unwrap();
+        rule.depends_on = deps.iter().map(|d| d.to_string()).collect();
+        rule
+    }
+
+    #[test]
+    fn depends_on_chains_are_accepted() {
+        let rules = [rule("app", &["jump"]), rule("jump", &["edge"]), rule("edge", &[]), rule("web", &["jump", "edge"])];
+        assert_eq!(check_depends_on(&rules), Ok(()));
+    }
+
+    #[test]
+    fn depends_on_unknown_rule() {
+        let rules = [rule("app", &["jump"]), rule("edge", &[])];
+        assert_eq!(check_depends_on(&rules), Err("rule app: depends_on names no rule: jump".to_string()));
+    }
+
+    #[test]
+    fn depends_on_itself() {
+        let rules = [rule("app", &["app"])];
+        assert_eq!(check_depends_on(&rules), Err("depends_on cycle: app -> app".to_string()));
+    }
+
+    #[test]
+    fn depends_on_cycle() {
+        let rules = [rule("a", &["b"]), rule("b", &["c"]), rule("c", &["a"]), rule("d", &[])];
+        assert_eq!(check_depends_on(&rules), Err("depends_on cycle: a -> b -> c -> a".to_string()));
+    }
+
+    #[test]
+    fn depends_on_unnamed_auto_loopback_rule() {
+        let mut jump = rule("", &[]);
+        jump.auto_loopback = true;
+        let default_name = jump.name();
+        let rules = [rule("app", &[default_name.as_str()]), jump.clone()];
+        assert_eq!(
+            check_depends_on(&rules),
+            Err(format!(
+                "rule app: depends_on names an auto_loopback rule without a name: {} (set its name)",
+                default_name
+            ))
+        );
+
+        jump.name = Some("jump".to_string());
+        let rules = [rule("app", &["jump"]), jump];
+        assert_eq!(check_depends_on(&rules), Ok(()));
+    }
+
+    #[test]
+    fn depends_on_on_demand_rule() {
+        let mut jump = rule("jump", &[]);
+        jump.mode = Mode::OnDemand;
+        let rules = [rule("app", &["jump"]), jump];
+        assert_eq!(check_depends_on(&rules), Err("rule app: depends_on names an on_demand rule: jump".to_string()));
+    }
+}
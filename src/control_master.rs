@@ -0,0 +1,505 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
+
+use tokio::process::Command;
+use tokio::sync::watch;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{sleep, Duration};
+use tracing::{error, info_span, warn, Instrument};
+
+use crate::config::{Backend, ForwardingRule};
+use crate::paths::{runtime_dir, stable_hash};
+use crate::ssh_args::{build_control_invocation, build_master_invocation, needs_gateway_ports, Invocation};
+use crate::supervisor::{supervise_subject, Subject};
+use crate::transport::{BoxFuture, ExitReason, SshTransport, Transport, TransportFactory};
+
+// How often to ask a freshly started master whether it is ready.
+const PROBE_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MasterState {
+    Connecting,
+    // Accepting -O requests; the number changes with every new master session,
+    // since forwards do not survive a master restart.
+    Up(u64),
+    Down,
+    AuthFailed,
+}
+
+struct Master {
+    // First rule seen for the profile; supplies host, port, credentials and extra args.
+    rule: ForwardingRule,
+    socket: PathBuf,
+    gateway_ports: bool,
+    state: Arc<watch::Sender<MasterState>>,
+}
+
+/// One OpenSSH ControlMaster per host profile (user, host, port, key, password,
+/// extra args). Rules attach to it with `ssh -O forward` / `-O cancel`, so
+/// adding, removing or restarting a rule never re-authenticates.
+pub struct ControlMasters {
+    masters: HashMap<u64, Master>,
+}
+
+impl ControlMasters {
+    pub fn new() -> Self {
+        Self {
+            masters: HashMap::new(),
+        }
+    }
+
+    /// Transport for a rule that runs its forward over its profile's master.
+    pub fn attach(&mut self, rule: &ForwardingRule) -> Result<Box<dyn Transport>, String> {
+        let mut forward = self.forwarder(rule)?;
+        forward(rule)
+    }
+
+    /// Like `attach`, but builds the forward for whichever variant of the rule
+    /// it is given, e.g. a front-proxied rule on a new internal port.
+    pub fn forwarder(&mut self, rule: &ForwardingRule) -> Result<TransportFactory, String> {
+        if rule.backend != Backend::Ssh {
+            return Err("control_master requires backend = \"ssh\"".to_string());
+        }
+        let key = profile_key(rule);
+        let master = match self.masters.get_mut(&key) {
+            Some(m) => m,
+            None => {
+                let dir = runtime_dir().map_err(|e| format!("runtime dir: {}", e))?;
+                // Socket paths are length-limited (~104 bytes); keep the name short.
+                let socket = dir.join(format!("cm-{:016x}.sock", key));
+                build_master_invocation(rule, &socket, false)?;
+                self.masters.entry(key).or_insert(Master {
+                    rule: rule.clone(),
+                    socket,
+                    gateway_ports: false,
+                    state: Arc::new(watch::channel(MasterState::Connecting).0),
+                })
+            }
+        };
+        master.gateway_ports |= needs_gateway_ports(rule);
+
+        let (socket, state) = (master.socket.clone(), master.state.clone());
+        Ok(Box::new(move |rule: &ForwardingRule| {
+            Ok(Box::new(ControlForward {
+                forward: build_control_invocation(rule, &socket, "forward")?,
+                cancel: build_control_invocation(rule, &socket, "cancel")?,
+                state: state.subscribe(),
+                session: None,
+                master_auth_failed: false,
+            }) as Box<dyn Transport>)
+        }))
+    }
+
+    /// Start the restart loop of every master that has rules attached.
+    pub fn spawn(self, join_set: &mut JoinSet<()>, shutdown: &watch::Receiver<bool>) {
+        for master in self.masters.into_values() {
+            let subject = Subject {
+                kind: "control master",
+                full: format!(
+                    "{}@{}:{} ({})",
+                    master.rule.ssh_user,
+                    master.rule.ssh_host,
+                    master.rule.ssh_port,
+                    master.socket.display()
+                ),
+                short: format!(
+                    "control master {}@{}:{}",
+                    master.rule.ssh_user, master.rule.ssh_host, master.rule.ssh_port
+                ),
+                status: None,
+                hooks: None,
+                depends_on: Vec::new(),
+            };
+            let mut transport = match master_transport(&master) {
+                Ok(t) => t,
+                Err(e) => {
+                    // Dropping the state sender fails the attached rules' start().
+                    error!("Config error for control master {}: {}", subject.full, e);
+                    continue;
+                }
+            };
+            let rx = shutdown.clone();
+            let span = info_span!(
+                "master",
+                host = %format_args!("{}@{}:{}", master.rule.ssh_user, master.rule.ssh_host, master.rule.ssh_port)
+            );
+            join_set.spawn(
+                async move {
+                    if let Err(e) = supervise_subject(&subject, transport.as_mut(), rx).await {
+                        error!("control master task error: {}", e);
+                    }
+                }
+                .instrument(span),
+            );
+        }
+    }
+}
+
+impl Default for ControlMasters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Identifies a host profile; hashed, so the password is not kept around in the key.
+fn profile_key(rule: &ForwardingRule) -> u64 {
+    let mut parts = vec![
+        rule.ssh_user.clone(),
+        rule.ssh_host.clone(),
+        rule.ssh_port.to_string(),
+        rule.ssh_key_path.clone().unwrap_or_default(),
+        rule.password().unwrap_or_default().to_string(),
+    ];
+    parts.extend(rule.ssh_extra_args.iter().cloned());
+    stable_hash(parts.join("\0").as_bytes())
+}
+
+fn master_transport(master: &Master) -> Result<Box<dyn Transport>, String> {
+    let inv = build_master_invocation(&master.rule, &master.socket, master.gateway_ports)?;
+    let inner = match master.rule.password() {
+        Some(pw) => SshTransport::pty(inv, pw.to_string()),
+        None => SshTransport::process(inv),
+    };
+    Ok(Box::new(MasterTransport {
+        inner,
+        check: build_control_invocation(&master.rule, &master.socket, "check")?,
+        socket: master.socket.clone(),
+        state: master.state.clone(),
+        sessions: 0,
+        probe: None,
+    }))
+}
+
+// The master ssh process plus readiness tracking for the rules attached to it.
+struct MasterTransport {
+    inner: SshTransport,
+    check: Invocation,
+    socket: PathBuf,
+    state: Arc<watch::Sender<MasterState>>,
+    sessions: u64,
+    probe: Option<JoinHandle<()>>,
+}
+
+impl MasterTransport {
+    fn stop_probe(&mut self) {
+        if let Some(probe) = self.probe.take() {
+            probe.abort();
+        }
+    }
+}
+
+impl Transport for MasterTransport {
+    fn start(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            // A socket left by a crashed master would make ssh skip multiplexing.
+            let _ = fs::remove_file(&self.socket);
+            self.state.send_replace(MasterState::Connecting);
+            self.inner.start().await?;
+
+            // The socket is only accepted once the master has authenticated.
+            self.sessions += 1;
+            let session = self.sessions;
+            let check = self.check.clone();
+            let state = self.state.clone();
+            self.probe = Some(tokio::spawn(async move {
+                loop {
+                    sleep(PROBE_INTERVAL).await;
+                    if run_quiet(&check).await.is_ok() {
+                        state.send_replace(MasterState::Up(session));
+                        return;
+                    }
+                }
+            }));
+            Ok(())
+        })
+    }
+
+    // Up once it accepts -O requests, which the attached rules wait for anyway.
+    fn ready(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            loop {
+                if *self.state.borrow() == MasterState::Up(self.sessions) {
+                    return Ok(());
+                }
+                if self.inner.ended() {
+                    return Err(io::Error::other("control master ended before it was ready"));
+                }
+                sleep(PROBE_INTERVAL).await;
+            }
+        })
+    }
+
+    fn wait(&mut self) -> BoxFuture<'_, ExitReason> {
+        Box::pin(async move {
+            let reason = self.inner.wait().await;
+            self.stop_probe();
+            self.state.send_replace(if reason == ExitReason::AuthFailed {
+                MasterState::AuthFailed
+            } else {
+                MasterState::Down
+            });
+            reason
+        })
+    }
+
+    fn kill(&mut self) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            self.stop_probe();
+            self.inner.kill().await;
+            self.state.send_replace(MasterState::Down);
+        })
+    }
+}
+
+// A rule's forward, added to and removed from a running master.
+struct ControlForward {
+    forward: Invocation,
+    cancel: Invocation,
+    state: watch::Receiver<MasterState>,
+    // Master session the forward was added to.
+    session: Option<u64>,
+    master_auth_failed: bool,
+}
+
+impl Transport for ControlForward {
+    fn start(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            // Wait for the master; the supervisor cancels this on shutdown.
+            let state = match self
+                .state
+                .wait_for(|s| matches!(s, MasterState::Up(_) | MasterState::AuthFailed))
+                .await
+            {
+                Ok(s) => *s,
+                Err(_) => return Err(io::Error::other("control master is not running")),
+            };
+            let session = match state {
+                MasterState::Up(session) => session,
+                _ => {
+                    // Reported from wait(), so the rule stops like any other auth failure.
+                    self.master_auth_failed = true;
+                    return Ok(());
+                }
+            };
+            run_quiet(&self.forward)
+                .await
+                .map_err(|e| io::Error::other(format!("ssh -O forward failed: {}", e)))?;
+            self.session = Some(session);
+            Ok(())
+        })
+    }
+
+    // `-O forward` only succeeds once the master has set up the forward.
+    fn ready(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            if self.master_auth_failed {
+                return Err(io::Error::other("control master authentication failed"));
+            }
+            Ok(())
+        })
+    }
+
+    fn wait(&mut self) -> BoxFuture<'_, ExitReason> {
+        Box::pin(async move {
+            if std::mem::take(&mut self.master_auth_failed) {
+                return ExitReason::AuthFailed;
+            }
+            let Some(session) = self.session else {
+                return ExitReason::Error("not started".to_string());
+            };
+            // The forward lives exactly as long as the master session it was added to.
+            let state = match self.state.wait_for(|s| *s != MasterState::Up(session)).await {
+                Ok(s) => *s,
+                Err(_) => MasterState::Down,
+            };
+            self.session = None;
+            match state {
+                MasterState::AuthFailed => ExitReason::AuthFailed,
+                // Same code ssh itself exits with when the connection drops.
+                _ => ExitReason::Exited { code: 255 },
+            }
+        })
+    }
+
+    fn kill(&mut self) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            self.master_auth_failed = false;
+            let Some(session) = self.session.take() else {
+                return;
+            };
+            if *self.state.borrow() != MasterState::Up(session) {
+                return;
+            }
+            if let Err(e) = run_quiet(&self.cancel).await {
+                warn!("ssh -O cancel failed: {}", e);
+            }
+        })
+    }
+}
+
+// Run a short-lived `ssh -O ...` command; Err carries its stderr.
+async fn run_quiet(inv: &Invocation) -> Result<(), String> {
+    let out = Command::new(&inv.program)
+        .args(&inv.args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|e| e.to_string())?;
+    if out.status.success() {
+        Ok(())
+    } else {
+        Err(String::from_utf8_lossy(&out.stderr).trim().to_string())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::status::{RuleState, StatusBoard};
+    use tokio::sync::broadcast;
+    use tokio::time::timeout;
+
+    fn sh(script: &str) -> Invocation {
+        Invocation {
+            program: "sh".to_string(),
+            args: vec!["-c".to_string(), script.to_string()],
+        }
+    }
+
+    // A master running `script`, whose -O check runs `check`.
+    fn master(script: &str, check: &str) -> MasterTransport {
+        MasterTransport {
+            inner: SshTransport::process(sh(script)),
+            check: sh(check),
+            socket: std::env::temp_dir().join(format!("ssh-tunnel-manager-test-{}.sock", std::process::id())),
+            state: Arc::new(watch::channel(MasterState::Connecting).0),
+            sessions: 0,
+            probe: None,
+        }
+    }
+
+    // A forward whose -O forward / cancel always succeed.
+    fn forward(state: &watch::Sender<MasterState>) -> ControlForward {
+        ControlForward {
+            forward: sh("true"),
+            cancel: sh("true"),
+            state: state.subscribe(),
+            session: None,
+            master_auth_failed: false,
+        }
+    }
+
+    async fn pending<T>(f: impl std::future::Future<Output = T>) -> bool {
+        timeout(Duration::from_millis(100), f).await.is_err()
+    }
+
+    #[tokio::test]
+    async fn master_states() {
+        let mut master = master("sleep 30", "true");
+        master.start().await.unwrap();
+        assert_eq!(*master.state.borrow(), MasterState::Connecting);
+        master.ready().await.unwrap();
+        assert_eq!(*master.state.borrow(), MasterState::Up(1));
+        master.kill().await;
+        assert_eq!(*master.state.borrow(), MasterState::Down);
+
+        // Every session is a new Up.
+        master.start().await.unwrap();
+        assert_eq!(*master.state.borrow(), MasterState::Connecting);
+        master.ready().await.unwrap();
+        assert_eq!(*master.state.borrow(), MasterState::Up(2));
+        master.kill().await;
+    }
+
+    #[tokio::test]
+    async fn master_that_never_takes_requests_is_not_ready() {
+        let mut master = master("sleep 1; exit 255", "false");
+        master.start().await.unwrap();
+        assert!(master.ready().await.is_err());
+        assert_eq!(*master.state.borrow(), MasterState::Connecting);
+        assert!(matches!(master.wait().await, ExitReason::Exited { .. }));
+        assert_eq!(*master.state.borrow(), MasterState::Down);
+    }
+
+    #[tokio::test]
+    async fn forward_lives_as_long_as_its_master_session() {
+        let state = watch::channel(MasterState::Connecting).0;
+        let mut forward = forward(&state);
+        // Added once the master is up.
+        assert!(pending(forward.start()).await);
+        state.send_replace(MasterState::Up(1));
+        forward.start().await.unwrap();
+        forward.ready().await.unwrap();
+        assert!(pending(forward.wait()).await);
+
+        // A new master session has none of the old forwards.
+        state.send_replace(MasterState::Up(2));
+        assert_eq!(forward.wait().await, ExitReason::Exited { code: 255 });
+        forward.start().await.unwrap();
+        assert_eq!(forward.session, Some(2));
+        assert!(pending(forward.wait()).await);
+
+        state.send_replace(MasterState::Down);
+        assert_eq!(forward.wait().await, ExitReason::Exited { code: 255 });
+        assert_eq!(forward.session, None);
+    }
+
+    #[tokio::test]
+    async fn master_auth_failure_reaches_its_forwards() {
+        let mut master = master(
+            "sleep 1; echo 'tester@bastion: Permission denied (publickey).' >&2; exit 255",
+            "true",
+        );
+        master.start().await.unwrap();
+        master.ready().await.unwrap();
+        let mut attached = forward(&master.state);
+        attached.start().await.unwrap();
+        attached.ready().await.unwrap();
+
+        assert_eq!(master.wait().await, ExitReason::AuthFailed);
+        assert_eq!(*master.state.borrow(), MasterState::AuthFailed);
+        assert_eq!(attached.wait().await, ExitReason::AuthFailed);
+
+        // Forwards started afterwards fail the same way instead of waiting.
+        let mut late = forward(&master.state);
+        late.start().await.unwrap();
+        assert!(late.ready().await.is_err());
+        assert_eq!(late.wait().await, ExitReason::AuthFailed);
+    }
+
+    #[tokio::test]
+    async fn attached_rules_stop_on_master_auth_failure() {
+        let state = Arc::new(watch::channel(MasterState::Up(1)).0);
+        let board = StatusBoard::new(broadcast::channel(16).0);
+        let subject = Subject {
+            kind: "ssh forward",
+            full: "db".to_string(),
+            short: "db".to_string(),
+            status: Some(board.register("db".to_string(), String::new(), false)),
+            hooks: None,
+            depends_on: Vec::new(),
+        };
+        let failing = state.clone();
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(300)).await;
+            failing.send_replace(MasterState::AuthFailed);
+        });
+        let (_shutdown, shutdown_rx) = watch::channel(false);
+        let mut forward = forward(&state);
+        timeout(Duration::from_secs(5), supervise_subject(&subject, &mut forward, shutdown_rx))
+            .await
+            .expect("supervisor stops retrying")
+            .unwrap();
+        let status = board.find("db").unwrap().snapshot();
+        assert_eq!(status.state, RuleState::AuthFailed);
+        assert_eq!((status.auth_failures, status.restarts), (1, 0));
+        assert!(status.last_connected.is_some());
+    }
+}
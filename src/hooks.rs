@@ -0,0 +1,167 @@
+use std::process::Stdio;
+use std::sync::Mutex;
+
+use tokio::process::Command;
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Duration};
+use tracing::{info, warn, Instrument};
+
+use crate::config::{ForwardKind, ForwardingRule};
+
+// Used when neither the rule nor [hooks] sets hook_timeout.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Hook {
+    Up,
+    Down,
+    AuthFailure,
+}
+
+impl Hook {
+    fn name(self) -> &'static str {
+        match self {
+            Hook::Up => "on_up",
+            Hook::Down => "on_down",
+            Hook::AuthFailure => "on_auth_failure",
+        }
+    }
+
+    // SSH_TUNNEL_EVENT
+    fn event(self) -> &'static str {
+        match self {
+            Hook::Up => "up",
+            Hook::Down => "down",
+            Hook::AuthFailure => "auth_failure",
+        }
+    }
+}
+
+// A rule's hook commands. They run through the shell, one at a time and in
+// order, without holding up the supervisor; output is logged under the rule.
+pub(crate) struct Hooks {
+    on_up: Option<String>,
+    on_down: Option<String>,
+    on_auth_failure: Option<String>,
+    timeout: Duration,
+    // Describes the rule to every command.
+    env: Vec<(&'static str, String)>,
+    // The hook started last; the next one waits for it.
+    last: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Hooks {
+    // None if the rule has no hook commands.
+    pub(crate) fn for_rule(rule: &ForwardingRule) -> Option<Self> {
+        if rule.on_up.is_none() && rule.on_down.is_none() && rule.on_auth_failure.is_none() {
+            return None;
+        }
+        let kind = match rule.kind {
+            ForwardKind::Local => "local",
+            ForwardKind::Remote => "remote",
+            ForwardKind::Dynamic => "dynamic",
+        };
+        Some(Self {
+            on_up: rule.on_up.clone(),
+            on_down: rule.on_down.clone(),
+            on_auth_failure: rule.on_auth_failure.clone(),
+            timeout: rule.hook_timeout.map(Duration::from_secs).unwrap_or(DEFAULT_TIMEOUT),
+            env: vec![
+                ("SSH_TUNNEL_RULE", rule.name()),
+                ("SSH_TUNNEL_KIND", kind.to_string()),
+                ("SSH_TUNNEL_LOCAL_HOST", rule.local_bind.clone()),
+                ("SSH_TUNNEL_LOCAL_PORT", rule.local_port.to_string()),
+                ("SSH_TUNNEL_REMOTE_ADDRESS", rule.remote_address.clone()),
+                ("SSH_TUNNEL_SSH_HOST", rule.ssh_host.clone()),
+            ],
+            last: Mutex::new(None),
+        })
+    }
+
+    // Start the command for `hook`, if configured, after the ones started before.
+    pub(crate) fn run(&self, hook: Hook, attempt: u32, reason: Option<&str>) {
+        let command = match hook {
+            Hook::Up => &self.on_up,
+            Hook::Down => &self.on_down,
+            Hook::AuthFailure => &self.on_auth_failure,
+        };
+        let Some(command) = command.clone() else {
+            return;
+        };
+        let mut cmd = shell(&command);
+        cmd.envs(self.env.iter().map(|(k, v)| (*k, v.as_str())))
+            .env("SSH_TUNNEL_EVENT", hook.event())
+            .env("SSH_TUNNEL_ATTEMPT", attempt.to_string())
+            .env("SSH_TUNNEL_REASON", reason.unwrap_or(""))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        let limit = self.timeout;
+        let mut last = self.last.lock().unwrap();
+        let previous = last.take();
+        *last = Some(tokio::spawn(
+            async move {
+                if let Some(previous) = previous {
+                    let _ = previous.await;
+                }
+                execute(hook, cmd, limit).await;
+            }
+            .in_current_span(),
+        ));
+    }
+
+    // Wait for the hooks started so far, e.g. the on_down of a rule being shut down.
+    pub(crate) async fn finish(&self) {
+        let last = self.last.lock().unwrap().take();
+        if let Some(last) = last {
+            let _ = last.await;
+        }
+    }
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+#[cfg(not(unix))]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
+
+async fn execute(hook: Hook, mut cmd: Command, limit: Duration) {
+    let child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            warn!(hook = hook.name(), "Cannot run {} hook: {}", hook.name(), e);
+            return;
+        }
+    };
+    // Dropping the child on timeout kills it (kill_on_drop).
+    let output = match timeout(limit, child.wait_with_output()).await {
+        Ok(Ok(output)) => output,
+        Ok(Err(e)) => {
+            warn!(hook = hook.name(), "{} hook failed: {}", hook.name(), e);
+            return;
+        }
+        Err(_) => {
+            warn!(hook = hook.name(), "{} hook timed out after {:?}; killed", hook.name(), limit);
+            return;
+        }
+    };
+    for out in [&output.stdout, &output.stderr] {
+        for line in String::from_utf8_lossy(out).lines().filter(|l| !l.trim().is_empty()) {
+            info!(target: "hook", hook = hook.name(), "{}", line.trim_end());
+        }
+    }
+    if output.status.success() {
+        info!(hook = hook.name(), "{} hook finished", hook.name());
+    } else {
+        warn!(hook = hook.name(), "{} hook exited with {}", hook.name(), output.status);
+    }
+}
@@ -0,0 +1,330 @@
+use std::io;
+use std::sync::{Arc, OnceLock};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::{timeout, Duration};
+use tokio_rustls::rustls::{self, pki_types::ServerName};
+use tokio_rustls::TlsConnector;
+use tracing::warn;
+
+// Minimal HTTP/1.x server for the manager's own endpoints (PAC file, metrics,
+// status API): one request per connection, no keep-alive, request bodies read
+// (so closing does not reset the connection) but ignored.
+// `post` is the matching client, for webhooks.
+
+// How long a client may take to send its request head.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// Larger request heads are refused.
+const HEAD_LIMIT: usize = 8 * 1024;
+// Larger request bodies are refused.
+const BODY_LIMIT: usize = 64 * 1024;
+
+pub(crate) struct Request {
+    pub method: String,
+    // Without the query string.
+    pub path: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+#[cfg(test)]
+impl Request {
+    pub fn new(method: &str, path: &str, headers: &[(&str, &str)]) -> Self {
+        Self {
+            method: method.to_string(),
+            path: path.to_string(),
+            headers: headers.iter().map(|(n, v)| (n.to_string(), v.to_string())).collect(),
+        }
+    }
+}
+
+pub(crate) struct Response {
+    pub status: &'static str,
+    pub content_type: &'static str,
+    pub body: String,
+    headers: Vec<(&'static str, String)>,
+}
+
+impl Response {
+    pub fn new(status: &'static str, content_type: &'static str, body: String) -> Self {
+        Self {
+            status,
+            content_type,
+            body,
+            headers: Vec::new(),
+        }
+    }
+
+    pub fn text(status: &'static str, body: &str) -> Self {
+        Self::new(status, "text/plain; charset=utf-8", format!("{}\n", body))
+    }
+
+    pub fn json<T: serde::Serialize>(status: &'static str, value: &T) -> Self {
+        match serde_json::to_string_pretty(value) {
+            Ok(body) => Self::new(status, "application/json", body + "\n"),
+            Err(e) => Self::text("500 Internal Server Error", &e.to_string()),
+        }
+    }
+
+    pub fn not_found() -> Self {
+        Self::text("404 Not Found", "not found")
+    }
+
+    pub fn method_not_allowed() -> Self {
+        Self::text("405 Method Not Allowed", "method not allowed")
+    }
+
+    pub fn with_header(mut self, name: &'static str, value: String) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+}
+
+pub(crate) async fn bind(listen: &str, what: &str) -> io::Result<TcpListener> {
+    TcpListener::bind(listen)
+        .await
+        .map_err(|e| io::Error::new(e.kind(), format!("{} listen {}: {}", what, listen, e)))
+}
+
+// Answer every request on `listener` with `handler` until shutdown. HEAD
+// responses are sent without their body.
+pub(crate) async fn serve<H>(listener: TcpListener, handler: H, mut shutdown: watch::Receiver<bool>) -> io::Result<()>
+where
+    H: Fn(&Request) -> Response + Send + Sync + 'static,
+{
+    let handler = Arc::new(handler);
+    let mut conns = JoinSet::new();
+    loop {
+        tokio::select! {
+            res = listener.accept() => match res {
+                Ok((stream, _)) => {
+                    let handler = handler.clone();
+                    conns.spawn(async move { handle(stream, handler.as_ref()).await });
+                }
+                Err(e) => warn!("http accept failed: {}", e),
+            },
+            Some(_) = conns.join_next() => {}
+            _ = shutdown.changed() => return Ok(()),
+        }
+    }
+}
+
+async fn handle<H>(mut stream: TcpStream, handler: &H)
+where
+    H: Fn(&Request) -> Response,
+{
+    let Ok(Ok(request)) = timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await else {
+        return;
+    };
+    let response = handler(&request);
+    let mut head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    );
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    let _ = stream.write_all(head.as_bytes()).await;
+    if request.method != "HEAD" {
+        let _ = stream.write_all(response.body.as_bytes()).await;
+    }
+    let _ = stream.shutdown().await;
+}
+
+async fn read_request(stream: &mut TcpStream) -> io::Result<Request> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() > HEAD_LIMIT {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "request head too large"));
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    let end = buf.windows(4).position(|w| w == b"\r\n\r\n").expect("read up to the blank line") + 4;
+    let head = String::from_utf8_lossy(&buf[..end]);
+    let mut lines = head.split("\r\n");
+    let mut parts = lines.next().unwrap_or_default().split(' ');
+    let method = parts.next().unwrap_or_default().to_string();
+    let target = parts.next().unwrap_or_default();
+    let path = target.split('?').next().unwrap_or_default().to_string();
+    let headers = lines
+        .take_while(|l| !l.is_empty())
+        .filter_map(|l| l.split_once(':'))
+        .map(|(n, v)| (n.trim().to_string(), v.trim().to_string()))
+        .collect();
+    let request = Request { method, path, headers };
+
+    // Read the body too: closing with unread data would send the client a
+    // reset, which can discard the response before it is read.
+    let length = match request.header("Content-Length") {
+        Some(length) => length
+            .parse::<usize>()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad Content-Length"))?,
+        None => 0,
+    };
+    if length > BODY_LIMIT {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "request body too large"));
+    }
+    let mut unread = length.saturating_sub(buf.len() - end);
+    while unread > 0 {
+        let n = stream.read(&mut chunk[..unread.min(1024)]).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        unread -= n;
+    }
+    Ok(request)
+}
+
+// POST `body` to an http:// or https:// URL and return the response status
+// code. The response body is not read.
+pub(crate) async fn post(url: &str, headers: &[(String, String)], body: &str) -> io::Result<u16> {
+    let (tls, rest) = if let Some(rest) = url.strip_prefix("https://") {
+        (true, rest)
+    } else if let Some(rest) = url.strip_prefix("http://") {
+        (false, rest)
+    } else {
+        return Err(invalid_url(url));
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = split_authority(authority, if tls { 443 } else { 80 }).ok_or_else(|| invalid_url(url))?;
+
+    let mut head = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+        path,
+        authority,
+        body.len()
+    );
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    head.push_str(body);
+
+    let stream = TcpStream::connect((host, port)).await?;
+    if tls {
+        let name = ServerName::try_from(host.to_string()).map_err(|_| invalid_url(url))?;
+        let stream = TlsConnector::from(tls_config()).connect(name, stream).await?;
+        exchange(stream, head.as_bytes()).await
+    } else {
+        exchange(stream, head.as_bytes()).await
+    }
+}
+
+fn invalid_url(url: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("invalid URL '{}'", url))
+}
+
+// "host", "host:port", "[v6]" or "[v6]:port".
+fn split_authority(authority: &str, default_port: u16) -> Option<(&str, u16)> {
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(rest) => {
+            let (host, after) = rest.split_once(']')?;
+            (host, after.strip_prefix(':'))
+        }
+        None => match authority.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+    let port = match port {
+        Some(port) => port.parse().ok()?,
+        None => default_port,
+    };
+    (!host.is_empty()).then_some((host, port))
+}
+
+// Server certificates are checked against the bundled Mozilla roots.
+fn tls_config() -> Arc<rustls::ClientConfig> {
+    static CONFIG: OnceLock<Arc<rustls::ClientConfig>> = OnceLock::new();
+    CONFIG
+        .get_or_init(|| {
+            let roots = rustls::RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            let config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .expect("ring supports the default protocol versions")
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            Arc::new(config)
+        })
+        .clone()
+}
+
+// Send the request and read the status code from the response's status line.
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, request: &[u8]) -> io::Result<u16> {
+    stream.write_all(request).await?;
+    stream.flush().await?;
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(2).any(|w| w == b"\r\n") {
+        if buf.len() > HEAD_LIMIT {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "response status line too long"));
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    // "HTTP/1.1 200 OK"
+    let line = String::from_utf8_lossy(&buf);
+    line.split(' ')
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed response status line"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The request as read by the server side of a local connection, and both ends.
+    async fn read(raw: &'static [u8]) -> (io::Result<Request>, TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        client.write_all(raw).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        (read_request(&mut server).await, server, client)
+    }
+
+    #[tokio::test]
+    async fn request_body_is_read() {
+        let (request, mut server, _client) =
+            read(b"POST /rules/db/stop?x=1 HTTP/1.1\r\nContent-Length: 11\r\nauthorization: Bearer t\r\n\r\n{\"a\": true}").await;
+        let request = request.unwrap();
+        assert_eq!((request.method.as_str(), request.path.as_str()), ("POST", "/rules/db/stop"));
+        assert_eq!(request.header("Authorization"), Some("Bearer t"));
+        // Nothing left unread that would turn the close into a reset.
+        let mut rest = [0u8; 1];
+        assert!(timeout(Duration::from_millis(100), server.read(&mut rest)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn bad_bodies_are_refused() {
+        let (request, _, _) = read(b"POST / HTTP/1.1\r\nContent-Length: 1048576\r\n\r\n").await;
+        assert_eq!(request.err().unwrap().to_string(), "request body too large");
+        let (request, _, _) = read(b"POST / HTTP/1.1\r\nContent-Length: x\r\n\r\n").await;
+        assert_eq!(request.err().unwrap().to_string(), "bad Content-Length");
+    }
+}
@@ -0,0 +1,369 @@
+use std::io;
+use std::net::IpAddr;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+
+use crate::acl::HostFilter;
+use crate::ssh_args::parse_host_port;
+
+// How long a client may take to send its request head.
+const HEAD_TIMEOUT: Duration = Duration::from_secs(30);
+// Larger request heads are refused.
+const HEAD_LIMIT: usize = 64 * 1024;
+// Request headers that only concern the proxy hop; never sent to the origin.
+const HOP_HEADERS: [&str; 4] = ["proxy-connection", "proxy-authorization", "connection", "keep-alive"];
+
+// Where a client asked to go and what to send once the SOCKS connection is up.
+pub(crate) struct ProxyRequest {
+    pub host: String,
+    pub port: u16,
+    // CONNECT: answer "200" and splice. Otherwise the request was an absolute-URI
+    // one and `head` is its origin-form rewrite.
+    connect: bool,
+    head: Vec<u8>,
+    // Bytes the client sent after the request head (start of body / TLS).
+    rest: Vec<u8>,
+}
+
+impl ProxyRequest {
+    pub fn target(&self) -> String {
+        match self.host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(_)) => format!("[{}]:{}", self.host, self.port),
+            _ => format!("{}:{}", self.host, self.port),
+        }
+    }
+}
+
+// Read the client's request head. On failure the client has been answered and
+// the audit close reason is returned.
+pub(crate) async fn read_request(client: &mut TcpStream) -> Result<ProxyRequest, String> {
+    let buf = match timeout(HEAD_TIMEOUT, read_head(client)).await {
+        Ok(Ok(buf)) => buf,
+        Ok(Err(e)) => {
+            if e.kind() == io::ErrorKind::InvalidData {
+                reply_error(client, "400 Bad Request", &e.to_string()).await;
+            }
+            return Err(format!("bad_request: {}", e));
+        }
+        Err(_) => {
+            reply_error(client, "408 Request Timeout", "no request received").await;
+            return Err("bad_request: timeout".to_string());
+        }
+    };
+    match parse_request(buf) {
+        Ok(request) => Ok(request),
+        Err(why) => {
+            reply_error(client, "400 Bad Request", why).await;
+            Err(format!("bad_request: {}", why))
+        }
+    }
+}
+
+// Check the request's target against the rule's `allow_hosts` / `deny_hosts`,
+// answering the client with 403 if it is not permitted.
+pub(crate) async fn check_host(request: &ProxyRequest, filter: &HostFilter, client: &mut TcpStream) -> bool {
+    if filter.permits(&request.host) {
+        return true;
+    }
+    reply_error(client, "403 Forbidden", &format!("{} is not allowed", request.host)).await;
+    false
+}
+
+// Ask the SOCKS server on `upstream` (the rule's `ssh -D`) for the request's
+// target, then answer the client / forward the request. Returns the number of
+// bytes sent upstream on the client's behalf.
+pub(crate) async fn open(request: &ProxyRequest, client: &mut TcpStream, upstream: &mut TcpStream) -> Result<u64, String> {
+    if let Err(e) = socks5_connect(upstream, &request.host, request.port).await {
+        reply_error(client, "502 Bad Gateway", &format!("{}: {}", request.target(), e)).await;
+        return Err(format!("error: socks: {}", e));
+    }
+    let io_error = |e: io::Error| format!("error: {}", e);
+    if request.connect {
+        client
+            .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+            .await
+            .map_err(io_error)?;
+    } else {
+        upstream.write_all(&request.head).await.map_err(io_error)?;
+    }
+    upstream.write_all(&request.rest).await.map_err(io_error)?;
+    Ok((request.head.len() + request.rest.len()) as u64)
+}
+
+pub(crate) async fn reply_error(client: &mut TcpStream, status: &str, message: &str) {
+    let body = format!("{}\n", message);
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    let _ = client.write_all(response.as_bytes()).await;
+    let _ = client.shutdown().await;
+}
+
+// Read up to and including the blank line that ends the request head.
+async fn read_head(client: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(4096);
+    let mut chunk = [0u8; 4096];
+    loop {
+        if buf.windows(4).any(|w| w == b"\r\n\r\n") {
+            return Ok(buf);
+        }
+        if buf.len() >= HEAD_LIMIT {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "request head too large"));
+        }
+        let n = client.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "closed before sending a request"));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+fn parse_request(buf: Vec<u8>) -> Result<ProxyRequest, &'static str> {
+    let end = buf.windows(4).position(|w| w == b"\r\n\r\n").expect("read_head stops at the blank line") + 4;
+    let head = std::str::from_utf8(&buf[..end]).map_err(|_| "request head is not valid UTF-8")?;
+    let rest = buf[end..].to_vec();
+    let mut lines = head.split("\r\n");
+    let mut parts = lines.next().unwrap_or_default().split(' ');
+    let (Some(method), Some(uri), Some(version), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return Err("malformed request line");
+    };
+    if version != "HTTP/1.1" && version != "HTTP/1.0" {
+        return Err("unsupported HTTP version");
+    }
+
+    if method.eq_ignore_ascii_case("CONNECT") {
+        let (host, port) = parse_host_port(uri).map_err(|_| "CONNECT needs host:port")?;
+        return Ok(ProxyRequest {
+            host,
+            port,
+            connect: true,
+            head: Vec::new(),
+            rest,
+        });
+    }
+
+    // Absolute-URI request: "GET http://host[:port]/path HTTP/1.1".
+    let after_scheme = match uri.get(..7) {
+        Some(scheme) if scheme.eq_ignore_ascii_case("http://") => &uri[7..],
+        _ => return Err("only CONNECT and http:// URIs are supported"),
+    };
+    let split = after_scheme.find(['/', '?']).unwrap_or(after_scheme.len());
+    let (authority, path) = after_scheme.split_at(split);
+    let (host, port) = match parse_host_port(authority) {
+        Ok(host_port) => host_port,
+        Err(_) => (authority.trim_start_matches('[').trim_end_matches(']').to_string(), 80),
+    };
+    if host.is_empty() {
+        return Err("URI without host");
+    }
+    let path = match path {
+        "" => "/".to_string(),
+        p if p.starts_with('?') => format!("/{}", p),
+        p => p.to_string(),
+    };
+
+    // Origin-form request line, hop-by-hop headers dropped, and one request per
+    // connection so every request gets its own host check.
+    let mut rewritten = format!("{} {} {}\r\n", method, path, version);
+    let mut has_host = false;
+    for line in lines.filter(|l| !l.is_empty()) {
+        let name = line.split_once(':').map(|(n, _)| n.trim()).unwrap_or(line);
+        if HOP_HEADERS.iter().any(|h| name.eq_ignore_ascii_case(h)) {
+            continue;
+        }
+        has_host |= name.eq_ignore_ascii_case("host");
+        rewritten.push_str(line);
+        rewritten.push_str("\r\n");
+    }
+    if !has_host {
+        rewritten.push_str(&format!("Host: {}\r\n", authority));
+    }
+    rewritten.push_str("Connection: close\r\n\r\n");
+    Ok(ProxyRequest {
+        host,
+        port,
+        connect: false,
+        head: rewritten.into_bytes(),
+        rest,
+    })
+}
+
+// SOCKS5 CONNECT (RFC 1928) without authentication, as offered by `ssh -D`.
+// The hostname is passed through so it is resolved on the SSH server side.
+async fn socks5_connect(stream: &mut TcpStream, host: &str, port: u16) -> io::Result<()> {
+    let protocol_error = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+
+    stream.write_all(&[5, 1, 0]).await?;
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).await?;
+    if method != [5, 0] {
+        return Err(protocol_error("SOCKS server requires authentication"));
+    }
+
+    let mut request = vec![5, 1, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let name = u8::try_from(host.len()).map_err(|_| protocol_error("host name too long"))?;
+            request.push(3);
+            request.push(name);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    // VER REP RSV ATYP, then the bound address, which is not needed.
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await.map_err(|e| match e.kind() {
+        // ssh closes the connection when the server cannot open the channel.
+        io::ErrorKind::UnexpectedEof => io::Error::new(io::ErrorKind::ConnectionRefused, "connect failed"),
+        _ => e,
+    })?;
+    if reply[1] != 0 {
+        return Err(io::Error::new(io::ErrorKind::ConnectionRefused, socks_error(reply[1])));
+    }
+    let addr_len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => stream.read_u8().await? as usize,
+        _ => return Err(protocol_error("malformed SOCKS reply")),
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+fn socks_error(code: u8) -> &'static str {
+    match code {
+        1 => "general SOCKS server failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "connect failed",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn parse(head: &str) -> Result<ProxyRequest, &'static str> {
+        parse_request(head.as_bytes().to_vec())
+    }
+
+    fn head(request: &ProxyRequest) -> &str {
+        std::str::from_utf8(&request.head).unwrap()
+    }
+
+    #[test]
+    fn absolute_form() {
+        let request = parse(
+            "GET http://example.com:8080/a?b=1 HTTP/1.1\r\nHost: example.com:8080\r\nProxy-Connection: keep-alive\r\nAccept: */*\r\n\r\nBODY",
+        )
+        .unwrap();
+        assert_eq!((request.host.as_str(), request.port, request.connect), ("example.com", 8080, false));
+        assert_eq!(
+            head(&request),
+            "GET /a?b=1 HTTP/1.1\r\nHost: example.com:8080\r\nAccept: */*\r\nConnection: close\r\n\r\n"
+        );
+        assert_eq!(request.rest, b"BODY");
+
+        let request = parse("POST http://example.com?q=1 HTTP/1.1\r\nHost: example.com\r\n\r\n").unwrap();
+        assert_eq!((request.host.as_str(), request.port), ("example.com", 80));
+        assert!(head(&request).starts_with("POST /?q=1 HTTP/1.1\r\n"));
+
+        let request = parse("GET http://[::1]:8080/ HTTP/1.1\r\nHost: [::1]:8080\r\n\r\n").unwrap();
+        assert_eq!(request.host, "::1");
+        assert_eq!(request.target(), "[::1]:8080");
+    }
+
+    #[test]
+    fn connect() {
+        let request = parse("CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n\x16\x03\x01").unwrap();
+        assert_eq!((request.host.as_str(), request.port, request.connect), ("example.com", 443, true));
+        assert!(request.head.is_empty());
+        // The start of the TLS handshake, sent once the tunnel is open.
+        assert_eq!(request.rest, b"\x16\x03\x01");
+        assert_eq!(request.target(), "example.com:443");
+
+        assert_eq!(parse("connect [::1]:22 HTTP/1.0\r\n\r\n").unwrap().target(), "[::1]:22");
+        assert_eq!(parse("CONNECT example.com HTTP/1.1\r\n\r\n").err(), Some("CONNECT needs host:port"));
+    }
+
+    #[test]
+    fn missing_host_header_is_added() {
+        let request = parse("GET http://example.com/index.html HTTP/1.0\r\nAccept: */*\r\n\r\n").unwrap();
+        assert_eq!(
+            head(&request),
+            "GET /index.html HTTP/1.0\r\nAccept: */*\r\nHost: example.com\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn lowercase_header_names() {
+        let request = parse(
+            "get HTTP://example.com/ HTTP/1.1\r\nhost: example.com\r\nproxy-authorization: Basic eDp5\r\nconnection: keep-alive\r\nkeep-alive: 300\r\n\r\n",
+        )
+        .unwrap();
+        // Host is not added a second time; hop-by-hop headers are dropped whatever their case.
+        assert_eq!(head(&request), "get / HTTP/1.1\r\nhost: example.com\r\nConnection: close\r\n\r\n");
+    }
+
+    #[test]
+    fn bad_requests() {
+        for (request, why) in [
+            ("GET /index.html HTTP/1.1\r\nHost: example.com\r\n\r\n", "only CONNECT and http:// URIs are supported"),
+            ("GET https://example.com/ HTTP/1.1\r\n\r\n", "only CONNECT and http:// URIs are supported"),
+            ("GET http:///index.html HTTP/1.1\r\n\r\n", "URI without host"),
+            ("GET http://example.com/ HTTP/2\r\n\r\n", "unsupported HTTP version"),
+            ("GET http://example.com/\r\n\r\n", "malformed request line"),
+            ("GET  http://example.com/ HTTP/1.1\r\n\r\n", "malformed request line"),
+        ] {
+            assert_eq!(parse(request).err(), Some(why), "{request:?}");
+        }
+        assert_eq!(
+            parse_request(b"GET http://example.com/ HTTP/1.1\r\nX: \xff\r\n\r\n".to_vec()).err(),
+            Some("request head is not valid UTF-8")
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_head_is_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let mut head = b"GET http://example.com/ HTTP/1.1\r\n".to_vec();
+            head.extend(std::iter::repeat_n(b'a', HEAD_LIMIT + 1024));
+            // The proxy stops reading at the limit, so the rest may not go out.
+            let _ = stream.write_all(&head).await;
+            let mut reply = Vec::new();
+            let _ = stream.read_to_end(&mut reply).await;
+            reply
+        });
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let err = read_request(&mut stream).await.err().unwrap();
+        assert_eq!(err, "bad_request: request head too large");
+        drop(stream);
+        let reply = client.await.unwrap();
+        assert!(reply.starts_with(b"HTTP/1.1 400 Bad Request\r\n"), "{}", String::from_utf8_lossy(&reply));
+    }
+}
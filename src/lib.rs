@@ -1,14 +1,71 @@
+mod acl;
+mod api;
+mod audit;
 pub mod config;
+pub mod control_master;
+mod hooks;
+mod http;
+mod http_proxy;
+mod limit;
+pub mod logging;
+mod loopback;
+mod manager;
+mod metrics;
+mod pac;
+mod paths;
+mod proxy;
+mod router;
 pub mod runner;
 pub mod ssh_args;
+pub mod status;
 pub mod supervisor;
+pub mod transport;
+pub mod webhook;
 
 use std::io;
 
-pub use config::{Config, ForwardingRule};
+use tokio::sync::broadcast;
 
+pub use config::{Backend, Config, ForwardKind, ForwardingRule, Mode};
+pub use manager::TunnelManager;
+pub use supervisor::{Event, EventKind};
+
+/// Kept for callers of the old entry point: runs the config at `config_path`,
+/// with its status file, until Ctrl-C or until no rule is running any more.
+/// This waits for Ctrl-C itself; to handle signals yourself (the binary does),
+/// drive a `TunnelManager` instead.
 pub async fn run(config_path: &str) -> io::Result<()> {
-    let config = config::load_config(config_path)?;
-    supervisor::run(config).await
+    run_with_events(config_path, broadcast::channel(1).0).await
+}
+
+/// Like `run`, and sends the lifecycle events of every rule on `events`
+/// (subscribe before calling, see `broadcast::Sender::subscribe`).
+pub async fn run_with_events(config_path: &str, events: broadcast::Sender<Event>) -> io::Result<()> {
+    let manager = TunnelManager::new(config::load_config(config_path)?)?;
+    tokio::spawn(forward_events(manager.subscribe(), events));
+    match status::status_file(config_path) {
+        Ok(path) => manager.write_status(path),
+        Err(e) => tracing::warn!("Status file disabled: {}", e),
+    }
+    manager.start().await?;
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = manager.finished() => {}
+    }
+    manager.shutdown().await;
+    Ok(())
 }
 
+// Pass the manager's events on until it is gone.
+async fn forward_events(mut rx: broadcast::Receiver<Event>, events: broadcast::Sender<Event>) {
+    loop {
+        match rx.recv().await {
+            // Fails only without receivers, which is fine.
+            Ok(event) => {
+                let _ = events.send(event);
+            }
+            Err(broadcast::error::RecvError::Lagged(n)) => tracing::warn!("{} lifecycle event(s) dropped", n),
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
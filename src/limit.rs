@@ -0,0 +1,87 @@
+use std::sync::Mutex;
+
+use tokio::time::{sleep, Duration, Instant};
+
+// Token bucket shared by all connections of a rule in one direction: refills at
+// `rate` bytes/sec and holds up to one second's worth.
+pub(crate) struct TokenBucket {
+    rate: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate: rate as f64,
+            state: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    // Account for `n` bytes, sleeping as long as the bucket is in debt.
+    // Returns whether the caller had to wait.
+    pub async fn take(&self, n: usize) -> bool {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let (tokens, last) = &mut *state;
+            let now = Instant::now();
+            *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.rate).min(self.rate);
+            *last = now;
+            // Going into debt lets a large read through at once; the next
+            // caller waits for it to be paid back.
+            *tokens -= n as f64;
+            if *tokens < 0.0 {
+                Duration::from_secs_f64(-*tokens / self.rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if wait.is_zero() {
+            return false;
+        }
+        sleep(wait).await;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Whether `take` waited, and for how long (ms of paused time).
+    async fn take(bucket: &TokenBucket, n: usize) -> (bool, u128) {
+        let start = Instant::now();
+        let waited = bucket.take(n).await;
+        (waited, start.elapsed().as_millis())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn burst_then_wait() {
+        let bucket = TokenBucket::new(1000);
+        // Starts full: one second's worth goes through at once.
+        assert_eq!(take(&bucket, 600).await, (false, 0));
+        assert_eq!(take(&bucket, 400).await, (false, 0));
+        assert_eq!(take(&bucket, 100).await, (true, 100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refills_up_to_one_second() {
+        let bucket = TokenBucket::new(1000);
+        assert_eq!(take(&bucket, 1000).await, (false, 0));
+        sleep(Duration::from_millis(250)).await;
+        assert_eq!(take(&bucket, 250).await, (false, 0));
+        // An idle minute still only buys one second's worth.
+        sleep(Duration::from_secs(60)).await;
+        assert_eq!(take(&bucket, 1000).await, (false, 0));
+        assert_eq!(take(&bucket, 1).await, (true, 1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn debt_is_paid_back_before_the_next_read() {
+        let bucket = TokenBucket::new(1000);
+        // A read larger than the bucket goes through, and waits for its debt.
+        assert_eq!(take(&bucket, 3000).await, (true, 2000));
+        // Paid off exactly: the next read waits only for itself.
+        assert_eq!(take(&bucket, 500).await, (true, 500));
+        assert_eq!(take(&bucket, 0).await, (false, 0));
+    }
+}
@@ -0,0 +1,48 @@
+use std::fs::OpenOptions;
+use std::io::{self, IsTerminal};
+use std::path::Path;
+use std::sync::Mutex;
+
+use tracing_subscriber::EnvFilter;
+
+// Diagnostics are `tracing` events; rule supervisors run inside a `tunnel` span
+// carrying the rule name, so runner output is attributed to its rule as well.
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, for log shippers
+    Json,
+}
+
+// Install the global subscriber. `level` is a filter directive such as "info"
+// or "warn,ssh_tunnel_manager::supervisor=debug". Logs go to stderr, or are
+// appended to `file` when given.
+pub fn init(format: LogFormat, level: &str, file: Option<&Path>) -> io::Result<()> {
+    let filter = EnvFilter::try_new(level)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid log level '{}': {}", level, e)))?;
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    let result = match (format, file) {
+        (LogFormat::Text, None) => builder
+            .with_target(false)
+            .with_ansi(io::stderr().is_terminal())
+            .with_writer(io::stderr)
+            .try_init(),
+        (LogFormat::Json, None) => builder.json().flatten_event(true).with_span_list(false).with_writer(io::stderr).try_init(),
+        (format, Some(path)) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| io::Error::new(e.kind(), format!("log file {}: {}", path.display(), e)))?;
+            let builder = builder.with_ansi(false).with_writer(Mutex::new(file));
+            match format {
+                LogFormat::Text => builder.with_target(false).try_init(),
+                LogFormat::Json => builder.json().flatten_event(true).with_span_list(false).try_init(),
+            }
+        }
+    };
+    result.map_err(|e| io::Error::other(format!("logging: {}", e)))
+}
@@ -0,0 +1,194 @@
+use std::collections::{BTreeSet, HashSet};
+use std::net::Ipv4Addr;
+
+use crate::config::{ForwardKind, ForwardingRule, LoopbackConfig};
+use crate::ssh_args::parse_host_port;
+
+// Loopback addresses handed out to `auto_loopback` rules, in config order, so a
+// rule keeps its address across restarts as long as the rules before it do.
+// Addresses of rules removed at runtime are handed out again, lowest first.
+pub(crate) struct LoopbackPool {
+    next: u32,
+    last: u32,
+    // Addresses other rules bind explicitly.
+    taken: HashSet<Ipv4Addr>,
+    freed: BTreeSet<Ipv4Addr>,
+}
+
+impl LoopbackPool {
+    pub fn new(config: &LoopbackConfig, rules: &[ForwardingRule]) -> Result<Self, String> {
+        let invalid = || format!("Invalid [loopback] range '{}': expected a CIDR inside 127.0.0.0/8", config.range);
+        let (addr, prefix) = config.range.split_once('/').ok_or_else(invalid)?;
+        let addr: Ipv4Addr = addr.trim().parse().map_err(|_| invalid())?;
+        let prefix: u32 = prefix.trim().parse().map_err(|_| invalid())?;
+        if !(8..=32).contains(&prefix) || !addr.is_loopback() {
+            return Err(invalid());
+        }
+        let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+        let network = u32::from(addr) & mask;
+        let broadcast = network | !mask;
+        // Network and broadcast addresses work on loopback, but look wrong in a mapping.
+        let (next, last) = if prefix < 31 { (network + 1, broadcast - 1) } else { (network, broadcast) };
+        let taken = rules
+            .iter()
+            .filter(|r| !r.auto_loopback)
+            .filter_map(|r| r.local_bind.parse().ok())
+            .chain([Ipv4Addr::LOCALHOST])
+            .collect();
+        Ok(Self {
+            next,
+            last,
+            taken,
+            freed: BTreeSet::new(),
+        })
+    }
+
+    // Give `rule` the next free loopback address as local_bind, and the remote
+    // port as local_port unless one is configured.
+    pub fn assign(&mut self, rule: &mut ForwardingRule) -> Result<(), String> {
+        if rule.kind == ForwardKind::Remote {
+            return Err("auto_loopback requires kind = \"local\" or \"dynamic\"".to_string());
+        }
+        if rule.local_port == 0 {
+            if rule.kind == ForwardKind::Dynamic {
+                return Err("auto_loopback with kind = \"dynamic\" needs a local_port".to_string());
+            }
+            rule.local_port = parse_host_port(&rule.remote_address)?.1;
+        }
+        let addr = match self.freed.pop_first() {
+            Some(addr) => addr,
+            None => self.take_next()?,
+        };
+        check_usable(addr)?;
+        rule.local_bind = addr.to_string();
+        Ok(())
+    }
+
+    // Hand back an address given out by `assign`.
+    pub fn release(&mut self, addr: Ipv4Addr) {
+        self.freed.insert(addr);
+    }
+
+    fn take_next(&mut self) -> Result<Ipv4Addr, String> {
+        loop {
+            if self.next > self.last {
+                return Err("no free address left in the [loopback] range".to_string());
+            }
+            let addr = Ipv4Addr::from(self.next);
+            self.next += 1;
+            if !self.taken.contains(&addr) {
+                return Ok(addr);
+            }
+        }
+    }
+}
+
+// Linux routes all of 127.0.0.0/8 to `lo`; other systems only configure
+// 127.0.0.1 unless aliases are added. Binding tells us which case we are in.
+fn check_usable(addr: Ipv4Addr) -> Result<(), String> {
+    match std::net::TcpListener::bind((addr, 0)) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!(
+            "loopback address {} is not usable ({}); on macOS / BSD add it with `sudo ifconfig lo0 alias {}`",
+            addr, e, addr
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(auto_loopback: bool, local_bind: &str) -> ForwardingRule {
+        let mut rule: ForwardingRule = toml::from_str(
+            r#"
+remote_address = "db.internal:5432"
+ssh_host = "bastion.example.com"
+ssh_user = "tester"
+"#,
+        )
+        .unwrap();
+        rule.auto_loopback = auto_loopback;
+        rule.local_bind = local_bind.to_string();
+        rule
+    }
+
+    fn new_pool(range: &str, rules: &[ForwardingRule]) -> Result<LoopbackPool, String> {
+        LoopbackPool::new(&LoopbackConfig { range: range.to_string() }, rules)
+    }
+
+    fn assign(pool: &mut LoopbackPool) -> Result<String, String> {
+        let mut rule = rule(true, "127.0.0.1");
+        pool.assign(&mut rule)?;
+        assert_eq!(rule.local_port, 5432);
+        Ok(rule.local_bind)
+    }
+
+    // Other systems than Linux only have the addresses that were added to lo0.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn assigns_in_order_around_taken_addresses() {
+        let rules = [rule(true, "127.0.0.1"), rule(false, "127.0.0.3"), rule(true, "127.0.0.1")];
+        let mut pool = new_pool("127.0.0.0/24", &rules).unwrap();
+        assert_eq!(assign(&mut pool).unwrap(), "127.0.0.2");
+        assert_eq!(assign(&mut pool).unwrap(), "127.0.0.4");
+        assert_eq!(assign(&mut pool).unwrap(), "127.0.0.5");
+
+        // A configured local_port is kept.
+        let mut fixed = rule(true, "127.0.0.1");
+        fixed.local_port = 15432;
+        pool.assign(&mut fixed).unwrap();
+        assert_eq!((fixed.local_bind.as_str(), fixed.local_port), ("127.0.0.6", 15432));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn freed_addresses_are_reused_lowest_first() {
+        let mut pool = new_pool("127.0.0.0/24", &[]).unwrap();
+        for expected in ["127.0.0.2", "127.0.0.3", "127.0.0.4"] {
+            assert_eq!(assign(&mut pool).unwrap(), expected);
+        }
+        pool.release(Ipv4Addr::new(127, 0, 0, 4));
+        pool.release(Ipv4Addr::new(127, 0, 0, 2));
+        assert_eq!(assign(&mut pool).unwrap(), "127.0.0.2");
+        assert_eq!(assign(&mut pool).unwrap(), "127.0.0.4");
+        assert_eq!(assign(&mut pool).unwrap(), "127.0.0.5");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn running_out_of_addresses() {
+        // .8 and .11 are the network and broadcast addresses.
+        let mut pool = new_pool("127.0.0.8/30", &[]).unwrap();
+        assert_eq!(assign(&mut pool).unwrap(), "127.0.0.9");
+        assert_eq!(assign(&mut pool).unwrap(), "127.0.0.10");
+        assert_eq!(assign(&mut pool).unwrap_err(), "no free address left in the [loopback] range");
+        pool.release(Ipv4Addr::new(127, 0, 0, 9));
+        assert_eq!(assign(&mut pool).unwrap(), "127.0.0.9");
+        assert!(assign(&mut pool).is_err());
+
+        // Without network and broadcast addresses to leave out, every address counts.
+        let mut pool = new_pool("127.0.0.9/32", &[]).unwrap();
+        assert_eq!(assign(&mut pool).unwrap(), "127.0.0.9");
+        assert!(assign(&mut pool).is_err());
+        // 127.0.0.1 is never handed out.
+        assert!(assign(&mut new_pool("127.0.0.1/32", &[]).unwrap()).is_err());
+    }
+
+    #[test]
+    fn bad_ranges_and_rules() {
+        for range in ["10.0.0.0/24", "127.0.0.0/7", "127.0.0.0/33", "127.0.0.1", "localhost/24"] {
+            assert!(new_pool(range, &[]).is_err(), "{}", range);
+        }
+        let mut pool = new_pool("127.0.0.0/24", &[]).unwrap();
+        let mut remote = rule(true, "127.0.0.1");
+        remote.kind = ForwardKind::Remote;
+        assert!(pool.assign(&mut remote).is_err());
+        let mut dynamic = rule(true, "127.0.0.1");
+        dynamic.kind = ForwardKind::Dynamic;
+        assert_eq!(
+            pool.assign(&mut dynamic).unwrap_err(),
+            "auto_loopback with kind = \"dynamic\" needs a local_port"
+        );
+    }
+}
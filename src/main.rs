@@ -1,5 +1,14 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::sync::broadcast;
+
+use ssh_tunnel_manager::logging::{self, LogFormat};
+use ssh_tunnel_manager::config::load_config;
+use ssh_tunnel_manager::status::{format_status, read_status, status_file, StatusReport};
+use ssh_tunnel_manager::{Event, TunnelManager};
 
 #[derive(Parser)]
 #[command(name = "ssh-tunnel-manager", version, about = "Manage SSH port forwarding from a TOML config")]
@@ -7,6 +16,36 @@ struct Cli {
     /// Path to the TOML configuration file
     #[arg(short, long, default_value = "config.toml", value_name = "PATH")]
     config: PathBuf,
+
+    /// Log output format
+    #[arg(long, value_enum, default_value_t = LogFormat::Text, value_name = "FORMAT")]
+    log_format: LogFormat,
+
+    /// Log level or filter directives (e.g. "debug", "warn,ssh_tunnel_manager::supervisor=info")
+    #[arg(long, default_value = "info", value_name = "LEVEL")]
+    log_level: String,
+
+    /// Append logs to this file instead of writing them to stderr
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// Print rule lifecycle events to stdout, one JSON object per line
+    #[arg(long)]
+    print_events: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Show the status of the manager running with this config
+    Status,
+    /// Show the recent ssh output of one rule of the running manager
+    Logs {
+        /// Rule name, as shown by `status`
+        rule: String,
+    },
 }
 
 #[tokio::main]
@@ -21,5 +60,120 @@ async fn main() -> std::io::Result<()> {
                 "config path is not valid UTF-8",
             )
         })?;
-    ssh_tunnel_manager::run(path).await
+    match cli.command {
+        None => {
+            logging::init(cli.log_format, &cli.log_level, cli.log_file.as_deref())?;
+            run(path, cli.print_events).await
+        }
+        Some(Command::Status) => print_status(path),
+        Some(Command::Logs { rule }) => print_logs(path, &rule),
+    }
+}
+
+async fn run(config_path: &str, show_events: bool) -> std::io::Result<()> {
+    let config = load_config(config_path)?;
+    let reload_pac = config.pac.is_some();
+    let manager = Arc::new(TunnelManager::new(config)?);
+    if show_events {
+        tokio::spawn(print_events(manager.subscribe()));
+    }
+    match status_file(config_path) {
+        Ok(path) => manager.write_status(path),
+        Err(e) => tracing::warn!("Status file disabled: {}", e),
+    }
+    manager.start().await?;
+    #[cfg(unix)]
+    if reload_pac {
+        tokio::spawn(reload_pac_on_hangup(config_path.to_string(), manager.clone()));
+    }
+    #[cfg(not(unix))]
+    let _ = reload_pac;
+
+    // Exit on Ctrl-C OR when all forwarding tasks finish (e.g. auth failure + no-retry).
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => tracing::info!("Shutting down..."),
+        _ = manager.finished() => tracing::info!("All forwarding tasks finished; exiting."),
+    }
+    manager.shutdown().await;
+    Ok(())
+}
+
+// Re-read the config on SIGHUP and serve the running rules' new pac_domains.
+#[cfg(unix)]
+async fn reload_pac_on_hangup(config_path: String, manager: Arc<TunnelManager>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            tracing::error!("pac reload error: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        let reloaded = load_config(&config_path)
+            .map_err(|e| e.to_string())
+            .and_then(|config| manager.reload_pac(&config));
+        match reloaded {
+            Ok(()) => tracing::info!("Reloaded PAC file from {}", config_path),
+            Err(e) => tracing::warn!("PAC reload from {} failed, keeping the current script: {}", config_path, e),
+        }
+    }
+}
+
+async fn print_events(mut rx: broadcast::Receiver<Event>) {
+    loop {
+        match rx.recv().await {
+            Ok(event) => match serde_json::to_string(&event) {
+                Ok(line) => println!("{}", line),
+                Err(e) => tracing::warn!("event: {}", e),
+            },
+            Err(broadcast::error::RecvError::Lagged(n)) => tracing::warn!("{} lifecycle event(s) dropped", n),
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+fn print_status(config_path: &str) -> std::io::Result<()> {
+    let report = running_status(config_path)?;
+    print!("{}", format_status(&report));
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    // The manager rewrites the file every few seconds while running.
+    let age = now.saturating_sub(report.updated);
+    if age > 10 {
+        eprintln!(
+            "Warning: status is {}s old; manager (pid {}) may have exited uncleanly",
+            age, report.pid
+        );
+    }
+    Ok(())
+}
+
+fn print_logs(config_path: &str, rule: &str) -> std::io::Result<()> {
+    let report = running_status(config_path)?;
+    let Some(status) = report.rules.iter().find(|r| r.name == rule) else {
+        eprintln!("No rule named {} in {}", rule, config_path);
+        std::process::exit(1);
+    };
+    if status.recent_output.is_empty() {
+        eprintln!("No ssh output recorded for {}", rule);
+    }
+    for line in &status.recent_output {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+fn running_status(config_path: &str) -> std::io::Result<StatusReport> {
+    match read_status(config_path) {
+        Ok(r) => Ok(r),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            eprintln!("No manager is running with {}", config_path);
+            std::process::exit(1);
+        }
+        Err(e) => Err(e),
+    }
 }
@@ -0,0 +1,511 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{sleep, Duration};
+use tracing::{error, info, warn, Instrument};
+
+use crate::api;
+use crate::audit::AuditLog;
+use crate::config::{
+    self, ApiConfig, AuditConfig, Config, ForwardingRule, HooksConfig, MetricsConfig, Mode, PacConfig,
+    RouterConfig, WebhookConfig,
+};
+use crate::control_master::ControlMasters;
+use crate::loopback::LoopbackPool;
+use crate::metrics;
+use crate::pac::{self, PacScript};
+use crate::proxy;
+use crate::router::{self, Routes};
+use crate::status::{Command, RuleStatus, StatusBoard, StatusReport};
+use crate::supervisor::{format_rule_full, rule_span, rule_subject, supervise_subject, Event};
+use crate::transport::{self, Transport};
+use crate::webhook;
+
+// How often the status file is rewritten.
+const STATUS_INTERVAL: Duration = Duration::from_secs(2);
+// Lifecycle events buffered per subscriber; a subscriber that falls behind misses the oldest.
+const EVENT_CAPACITY: usize = 256;
+// Router clients waiting to be taken by a front-proxied rule.
+const ROUTED_QUEUE: usize = 64;
+
+/// Runs forwarding rules next to the manager's own services (`[router]`, `[pac]`,
+/// `[metrics]`, `[api]`, status file), and lets rules be added, removed and
+/// restarted while running.
+///
+/// Create it with `new`, `subscribe` to lifecycle events if needed, then `start`
+/// it. Signals are up to the caller: call `shutdown` on Ctrl-C.
+pub struct TunnelManager {
+    // Services and initial rules, until `start`.
+    pending: Mutex<Option<Pending>>,
+    board: Arc<StatusBoard>,
+    events: broadcast::Sender<Event>,
+    shutdown: watch::Sender<bool>,
+    rules: Mutex<Rules>,
+    pac: Arc<PacScript>,
+    // Number of rule tasks still running, for `finished`.
+    active: watch::Sender<usize>,
+    services: Mutex<JoinSet<()>>,
+    status_file: Mutex<Option<PathBuf>>,
+}
+
+struct Pending {
+    forwarding: Vec<ForwardingRule>,
+    router: Option<RouterConfig>,
+    pac: Option<PacConfig>,
+    metrics: Option<MetricsConfig>,
+    api: Option<ApiConfig>,
+    webhooks: Vec<WebhookConfig>,
+}
+
+struct Rules {
+    tasks: HashMap<String, RuleTask>,
+    // Rules with auto_loopback get their local_bind (and local_port) from here.
+    loopback: LoopbackPool,
+    audit_config: AuditConfig,
+    // One audit log for all rules with audit_log = true, opened for the first one.
+    audit: Option<Arc<AuditLog>>,
+    // Hooks of the rules that have none of their own.
+    hooks: HooksConfig,
+}
+
+struct RuleTask {
+    stop: watch::Sender<bool>,
+    handle: JoinHandle<()>,
+    depends_on: Vec<String>,
+    on_demand: bool,
+    // Given by the loopback pool (auto_loopback), handed back when the rule is removed.
+    loopback: Option<Ipv4Addr>,
+}
+
+// What only the rules of the initial config can use: shared ssh masters are
+// spawned and the router's routes fixed when the manager starts.
+struct Startup<'a> {
+    masters: &'a mut ControlMasters,
+    routes: &'a mut Routes,
+}
+
+impl TunnelManager {
+    /// Check the config and prepare the manager; nothing runs until `start`.
+    pub fn new(config: Config) -> io::Result<Self> {
+        let config_error = |e: String| {
+            error!("Config error: {}", e);
+            io::Error::new(io::ErrorKind::InvalidInput, e)
+        };
+        let loopback = LoopbackPool::new(&config.loopback, &config.forwarding).map_err(config_error)?;
+        config::check_depends_on(&config.forwarding).map_err(config_error)?;
+        let events = broadcast::Sender::new(EVENT_CAPACITY);
+        Ok(Self {
+            pending: Mutex::new(Some(Pending {
+                forwarding: config.forwarding,
+                router: config.router,
+                pac: config.pac,
+                metrics: config.metrics,
+                api: config.api,
+                webhooks: config.webhook,
+            })),
+            // The board sends the events of every rule.
+            board: Arc::new(StatusBoard::new(events.clone())),
+            events,
+            shutdown: watch::Sender::new(false),
+            rules: Mutex::new(Rules {
+                tasks: HashMap::new(),
+                loopback,
+                audit_config: config.audit,
+                audit: None,
+                hooks: config.hooks,
+            }),
+            pac: Arc::new(PacScript::new()),
+            active: watch::Sender::new(0),
+            services: Mutex::new(JoinSet::new()),
+            status_file: Mutex::new(None),
+        })
+    }
+
+    /// Start the rules of the config and the configured services. Rules with
+    /// config errors are logged and skipped, and so are the rules depending on them.
+    pub async fn start(&self) -> io::Result<()> {
+        let Some(pending) = self.pending.lock().unwrap().take() else {
+            return Err(io::Error::other("the manager was already started"));
+        };
+        info!(rules = pending.forwarding.len(), "Loaded {} forwarding rule(s)", pending.forwarding.len());
+
+        if pending.forwarding.iter().any(|r| r.audit_log) {
+            // Audited rules must not run unrecorded.
+            if let Err(e) = self.rules.lock().unwrap().open_audit() {
+                error!("Cannot open audit log: {}", e);
+                return Err(e);
+            }
+        }
+
+        let shutdown = self.shutdown.subscribe();
+        let mut services = self.services.lock().unwrap();
+        // Subscribed before the first rule starts, so no state change is missed.
+        for webhook_config in pending.webhooks {
+            let (events, rx) = (self.events.subscribe(), shutdown.clone());
+            services.spawn(async move {
+                if let Err(e) = webhook::run(webhook_config, events, rx).await {
+                    error!("webhook error: {}", e);
+                }
+            });
+        }
+
+        let mut masters = ControlMasters::new();
+        let mut routes = Routes::new();
+        let has_pac_domains = pending.forwarding.iter().any(|r| !r.pac_domains.is_empty());
+        let mut failed = Vec::new();
+        for rule in pending.forwarding {
+            let name = rule.name();
+            let startup = Startup {
+                masters: &mut masters,
+                routes: &mut routes,
+            };
+            if let Err(e) = self.launch(rule, Some(startup)) {
+                error!("Config error for {}", e);
+                failed.push(name);
+            }
+        }
+
+        masters.spawn(&mut services, &shutdown);
+
+        match pending.router {
+            Some(router_config) => {
+                let rx = shutdown.clone();
+                services.spawn(async move {
+                    if let Err(e) = router::run(router_config, routes, rx).await {
+                        error!("router error: {}", e);
+                    }
+                });
+            }
+            None if !routes.is_empty() => {
+                warn!("Rules have hostnames but no [router] is configured");
+            }
+            None => {}
+        }
+
+        match pending.pac {
+            Some(pac_config) => {
+                let (script, rx) = (self.pac.subscribe(), shutdown.clone());
+                services.spawn(async move {
+                    if let Err(e) = pac::run(pac_config, script, rx).await {
+                        error!("pac error: {}", e);
+                    }
+                });
+            }
+            None if has_pac_domains => {
+                warn!("Rules have pac_domains but no [pac] is configured");
+            }
+            None => {}
+        }
+
+        if let Some(metrics_config) = pending.metrics {
+            let (board, rx) = (self.board.clone(), shutdown.clone());
+            services.spawn(async move {
+                if let Err(e) = metrics::run(metrics_config, board, rx).await {
+                    error!("metrics error: {}", e);
+                }
+            });
+        }
+
+        if let Some(api_config) = pending.api {
+            let (board, rx) = (self.board.clone(), shutdown.clone());
+            services.spawn(async move {
+                if let Err(e) = api::run(api_config, board, rx).await {
+                    error!("api error: {}", e);
+                }
+            });
+        }
+
+        // Rules depending on a skipped rule would wait for it forever.
+        while let Some(name) = failed.pop() {
+            let dependents: Vec<(String, RuleTask)> =
+                self.rules.lock().unwrap().tasks.extract_if(|_, t| t.depends_on.contains(&name)).collect();
+            for (dependent, task) in dependents {
+                error!(rule = %dependent, "Not starting {}: it depends on {}, which was skipped", dependent, name);
+                let _ = task.stop.send(true);
+                services.spawn(async move {
+                    let _ = task.handle.await;
+                });
+                self.board.remove(&dependent);
+                self.pac.remove(&dependent);
+                failed.push(dependent);
+            }
+        }
+        Ok(())
+    }
+
+    /// Start one more rule. Its name must not be in use by a running rule; one
+    /// that ended by itself (e.g. after an authentication failure) is replaced.
+    /// `hostnames` and `control_master` are only available to rules of the
+    /// initial config: the router's routes and the shared ssh masters are fixed
+    /// once the manager has started.
+    ///
+    /// Returns once the rule is registered (it shows in `status`) and its task
+    /// is spawned, before it connects; watch `subscribe` for `Connected`.
+    pub fn add_rule(&self, rule: ForwardingRule) -> Result<(), String> {
+        self.launch(rule, None)
+    }
+
+    /// Stop a rule and wait until it has shut down, then forget it. Rules that
+    /// others depend on are refused; remove their dependents first.
+    pub async fn remove_rule(&self, name: &str) -> Result<(), String> {
+        let task = {
+            let mut rules = self.rules.lock().unwrap();
+            if let Some((dependent, _)) = rules.tasks.iter().find(|(_, t)| t.depends_on.iter().any(|d| d == name)) {
+                return Err(format!("rule {} depends on {}", dependent, name));
+            }
+            rules.tasks.remove(name)
+        };
+        let task = task.ok_or_else(|| format!("no rule named {}", name))?;
+        let _ = task.stop.send(true);
+        let _ = task.handle.await;
+        if let Some(addr) = task.loopback {
+            self.rules.lock().unwrap().loopback.release(addr);
+        }
+        self.board.remove(name);
+        self.pac.remove(name);
+        info!(rule = %name, "Removed rule {}", name);
+        Ok(())
+    }
+
+    /// End the rule's session and reconnect right away. Returns once the
+    /// request is sent, before the new session starts; watch `subscribe` for it.
+    pub fn restart_rule(&self, name: &str) -> Result<(), String> {
+        self.command(name, Command::Restart)
+    }
+
+    /// Send a start / stop / restart request to a rule, as the status API does.
+    pub fn command(&self, name: &str, command: Command) -> Result<(), String> {
+        let rule = self.board.find(name).ok_or_else(|| format!("no rule named {}", name))?;
+        rule.command(command)
+    }
+
+    pub fn status(&self) -> StatusReport {
+        self.board.report()
+    }
+
+    pub fn rule_status(&self, name: &str) -> Option<RuleStatus> {
+        self.board.find(name).map(|r| r.snapshot())
+    }
+
+    /// Lifecycle events of every rule from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Keep the status of every rule in `path` (read by `status` / `logs`)
+    /// until shutdown, then remove it.
+    pub fn write_status(&self, path: PathBuf) {
+        *self.status_file.lock().unwrap() = Some(path.clone());
+        let board = self.board.clone();
+        let mut rx = self.shutdown.subscribe();
+        self.services.lock().unwrap().spawn(async move {
+            loop {
+                if let Err(e) = board.write(&path) {
+                    warn!("write status file {}: {}", path.display(), e);
+                }
+                tokio::select! {
+                    _ = sleep(STATUS_INTERVAL) => {}
+                    _ = rx.changed() => break,
+                }
+            }
+        });
+    }
+
+    /// Take the `pac_domains` of the running rules from a re-read config.
+    pub fn reload_pac(&self, config: &Config) -> Result<(), String> {
+        self.pac.reload(config)
+    }
+
+    /// Resolves once no rule is running any more (e.g. all stopped after
+    /// authentication failures).
+    pub async fn finished(&self) {
+        let mut active = self.active.subscribe();
+        let _ = active.wait_for(|n| *n == 0).await;
+    }
+
+    /// Stop every rule and wait for them, then stop the services.
+    pub async fn shutdown(&self) {
+        let tasks: Vec<RuleTask> = self.rules.lock().unwrap().tasks.drain().map(|(_, t)| t).collect();
+        for task in &tasks {
+            let _ = task.stop.send(true);
+        }
+        for task in tasks {
+            let _ = task.handle.await;
+        }
+        // Masters (and the router / status writer) outlive their rules only until here.
+        let _ = self.shutdown.send(true);
+        let mut services = std::mem::take(&mut *self.services.lock().unwrap());
+        while services.join_next().await.is_some() {}
+        let audit = self.rules.lock().unwrap().audit.take();
+        if let Some(audit) = audit {
+            audit.close().await;
+        }
+        if let Some(path) = self.status_file.lock().unwrap().take() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    // Check a rule and spawn its task; Err describes the rule and the problem.
+    fn launch(&self, mut rule: ForwardingRule, startup: Option<Startup<'_>>) -> Result<(), String> {
+        let mut rules = self.rules.lock().unwrap();
+        rules.hooks.apply(&mut rule);
+        let mut loopback = None;
+        if rule.auto_loopback {
+            rules.loopback.assign(&mut rule).map_err(|e| fail(&rule, e))?;
+            info!(rule = %rule.name(), "Loopback address assigned: {}", format_rule_full(&rule));
+            loopback = rule.local_bind.parse().ok();
+        } else if rule.local_port == 0 {
+            return Err(fail(&rule, "local_port is required".to_string()));
+        }
+        let at_startup = startup.is_some();
+        let res = self.spawn_rule(&mut rules, rule, startup, loopback);
+        // Rules of the config keep their addresses in config order, past one that fails too.
+        if let (Err(_), Some(addr), false) = (&res, loopback, at_startup) {
+            rules.loopback.release(addr);
+        }
+        res
+    }
+
+    fn spawn_rule(
+        &self,
+        rules: &mut Rules,
+        rule: ForwardingRule,
+        mut startup: Option<Startup<'_>>,
+        loopback: Option<Ipv4Addr>,
+    ) -> Result<(), String> {
+        let name = rule.name();
+        // A rule that ended by itself (e.g. after an authentication failure) gives up its name.
+        if rules.tasks.get(&name).is_some_and(|t| t.handle.is_finished()) {
+            if let Some(addr) = rules.tasks.remove(&name).and_then(|t| t.loopback) {
+                rules.loopback.release(addr);
+            }
+            self.board.remove(&name);
+            self.pac.remove(&name);
+        }
+        if rules.tasks.contains_key(&name) {
+            return Err(fail(&rule, format!("a rule named {} is already running", name)));
+        }
+        // A host filter that nothing enforces must not look like one that does.
+        if !rule.http_proxy && (!rule.allow_hosts.is_empty() || !rule.deny_hosts.is_empty()) {
+            return Err(fail(&rule, "allow_hosts / deny_hosts require http_proxy = true".to_string()));
+        }
+        match &mut startup {
+            Some(startup) => startup.routes.check(&rule).map_err(|e| fail(&rule, e))?,
+            // The router's routes and the shared masters are set up once, by `start`.
+            None if !rule.hostnames.is_empty() || rule.control_master => {
+                return Err(fail(
+                    &rule,
+                    "hostnames and control_master are only available to rules in the config (the router and \
+                     the shared ssh masters are set up when the manager starts)"
+                        .to_string(),
+                ));
+            }
+            // Dependencies of the config were checked with it; these must already run.
+            None => {
+                for dep in &rule.depends_on {
+                    match rules.tasks.get(dep).filter(|t| !t.handle.is_finished()) {
+                        Some(task) if task.on_demand => {
+                            return Err(fail(&rule, format!("depends_on names an on_demand rule: {}", dep)));
+                        }
+                        Some(_) => {}
+                        None => return Err(fail(&rule, format!("depends_on names no running rule: {}", dep))),
+                    }
+                }
+            }
+        }
+        let pac_entry = pac::entry(&rule).map_err(|e| fail(&rule, e))?;
+        if rule.audit_log {
+            rules.open_audit().map_err(|e| fail(&rule, format!("cannot open audit log: {}", e)))?;
+        }
+        // Front-proxied rules run the tunnel on an internal port behind the manager's listener.
+        let proxied = proxy::uses_front_proxy(&rule);
+        let transport = if proxied {
+            proxy::internal_rule(&rule).and_then(|internal| {
+                let build = match &mut startup {
+                    Some(startup) if internal.control_master => startup.masters.forwarder(&internal)?,
+                    _ => Box::new(transport::for_rule),
+                };
+                let tunnel = proxy::InternalTunnel::new(internal, build)?;
+                Ok((Some(tunnel.port()), Box::new(tunnel) as Box<dyn Transport>))
+            })
+        } else {
+            match &mut startup {
+                Some(startup) if rule.control_master => startup.masters.attach(&rule),
+                _ => transport::for_rule(&rule),
+            }
+            .map(|t| (None, t))
+        };
+        let (internal_port, mut transport) = transport.map_err(|e| fail(&rule, e))?;
+        // Routed only once the rule is sure to run, so no route is left pointing nowhere.
+        let mut routed = None;
+        if let Some(startup) = &mut startup {
+            let proxy = if proxied && !rule.hostnames.is_empty() {
+                let (tx, rx) = mpsc::channel(ROUTED_QUEUE);
+                routed = Some(rx);
+                Some(tx)
+            } else {
+                None
+            };
+            startup.routes.add(&rule, proxy).map_err(|e| fail(&rule, e))?;
+        }
+
+        if let Some(entry) = pac_entry {
+            self.pac.add(entry);
+        }
+        let status = self.board.register(name.clone(), format_rule_full(&rule), proxied);
+        transport.record_output(status.output.clone());
+        let audit = rules.audit.clone().filter(|_| rule.audit_log);
+        let (stop, rx) = watch::channel(false);
+        let active = self.active.clone();
+        active.send_modify(|n| *n += 1);
+        let (depends_on, on_demand) = (rule.depends_on.clone(), rule.mode == Mode::OnDemand);
+        let span = rule_span(&rule);
+        let handle = tokio::spawn(
+            async move {
+                let res = if let Some(internal_port) = internal_port {
+                    proxy::run(rule, internal_port, transport, status, audit, routed, rx).await
+                } else {
+                    let subject = rule_subject(&rule, Some(status));
+                    supervise_subject(&subject, transport.as_mut(), rx).await
+                };
+                if let Err(e) = res {
+                    error!("forwarding task error: {}", e);
+                }
+                active.send_modify(|n| *n -= 1);
+            }
+            .instrument(span),
+        );
+        rules.tasks.insert(
+            name,
+            RuleTask {
+                stop,
+                handle,
+                depends_on,
+                on_demand,
+                loopback,
+            },
+        );
+        Ok(())
+    }
+}
+
+// Error for a rule that cannot be launched: the rule, then the problem.
+fn fail(rule: &ForwardingRule, e: String) -> String {
+    format!("{}: {}", format_rule_full(rule), e)
+}
+
+impl Rules {
+    fn open_audit(&mut self) -> io::Result<()> {
+        if self.audit.is_none() {
+            let log = AuditLog::open(&self.audit_config)?;
+            info!("Audit log: {}", log.path().display());
+            self.audit = Some(Arc::new(log));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,275 @@
+use std::fmt::Write;
+use std::io;
+use std::sync::Arc;
+
+use tokio::sync::watch;
+use tracing::info;
+
+use crate::config::MetricsConfig;
+use crate::http::{self, Request, Response};
+use crate::status::{RuleState, RuleStatus, StatusBoard, StatusReport};
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+const STATES: [RuleState; 7] = [
+    RuleState::Idle,
+    RuleState::Waiting,
+    RuleState::Starting,
+    RuleState::Running,
+    RuleState::Backoff,
+    RuleState::AuthFailed,
+    RuleState::Stopped,
+];
+
+// Serve the status board in Prometheus text format on `[metrics] listen`.
+pub(crate) async fn run(
+    config: MetricsConfig,
+    board: Arc<StatusBoard>,
+    shutdown: watch::Receiver<bool>,
+) -> io::Result<()> {
+    let listener = http::bind(&config.listen, "metrics").await?;
+    info!("Serving metrics on http://{}/metrics", config.listen);
+    http::serve(
+        listener,
+        move |request: &Request| match (request.method.as_str(), request.path.as_str()) {
+            ("GET" | "HEAD", "/metrics") => Response::new("200 OK", CONTENT_TYPE, render(&board.report())),
+            (_, "/metrics") => Response::method_not_allowed(),
+            _ => Response::not_found(),
+        },
+        shutdown,
+    )
+    .await
+}
+
+fn render(report: &StatusReport) -> String {
+    let mut out = String::new();
+    let rules = &report.rules;
+    let now = report.updated;
+
+    family(&mut out, "ssh_tunnel_up", "gauge", "Whether the rule's tunnel is connected.", rules, |r| {
+        Some(u64::from(r.state == RuleState::Running))
+    });
+    header(&mut out, "ssh_tunnel_state", "gauge", "Current state of the rule (1 for the current state).");
+    for r in rules {
+        for state in STATES {
+            let _ = writeln!(
+                out,
+                "ssh_tunnel_state{{rule=\"{}\",state=\"{}\"}} {}",
+                escape(&r.name),
+                state.as_str(),
+                u8::from(r.state == state)
+            );
+        }
+    }
+    family(&mut out, "ssh_tunnel_restarts_total", "counter", "Restarts after the tunnel ended.", rules, |r| {
+        Some(r.restarts)
+    });
+    family(
+        &mut out,
+        "ssh_tunnel_auth_failures_total",
+        "counter",
+        "Sessions ended by rejected credentials.",
+        rules,
+        |r| Some(r.auth_failures),
+    );
+    family(
+        &mut out,
+        "ssh_tunnel_backoff_seconds",
+        "gauge",
+        "Delay before the next connection attempt (0 unless in backoff).",
+        rules,
+        |r| Some(r.backoff_secs),
+    );
+    family(
+        &mut out,
+        "ssh_tunnel_seconds_since_connect",
+        "gauge",
+        "Seconds since the tunnel last came up.",
+        rules,
+        |r| r.last_connected.map(|t| now.saturating_sub(t)),
+    );
+    header(&mut out, "ssh_tunnel_last_exit_info", "gauge", "Why the last session ended.");
+    for r in rules {
+        if let Some(reason) = &r.last_exit {
+            let _ = writeln!(
+                out,
+                "ssh_tunnel_last_exit_info{{rule=\"{}\",reason=\"{}\"}} 1",
+                escape(&r.name),
+                escape(reason)
+            );
+        }
+    }
+
+    // Front-proxied rules only.
+    family(
+        &mut out,
+        "ssh_tunnel_connections_active",
+        "gauge",
+        "Open client connections.",
+        rules,
+        |r| r.traffic.as_ref().map(|t| t.active_connections),
+    );
+    family(
+        &mut out,
+        "ssh_tunnel_connections_total",
+        "counter",
+        "Accepted client connections.",
+        rules,
+        |r| r.traffic.as_ref().map(|t| t.total_connections),
+    );
+    family(
+        &mut out,
+        "ssh_tunnel_connections_rejected_total",
+        "counter",
+        "Client connections refused because max_connections were open.",
+        rules,
+        |r| r.traffic.as_ref().map(|t| t.rejected_over_limit),
+    );
+    header(&mut out, "ssh_tunnel_bytes_total", "counter", "Bytes relayed (up: client to remote).");
+    for r in rules {
+        if let Some(t) = &r.traffic {
+            for (direction, bytes) in [("up", t.bytes_up), ("down", t.bytes_down)] {
+                let _ = writeln!(
+                    out,
+                    "ssh_tunnel_bytes_total{{rule=\"{}\",direction=\"{}\"}} {}",
+                    escape(&r.name),
+                    direction,
+                    bytes
+                );
+            }
+        }
+    }
+    family(
+        &mut out,
+        "ssh_tunnel_throttled_reads_total",
+        "counter",
+        "Reads delayed by rate_limit_up / rate_limit_down.",
+        rules,
+        |r| r.traffic.as_ref().map(|t| t.throttled),
+    );
+    out
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+// One sample per rule, labelled with the rule name; rules without a value are left out.
+fn family(out: &mut String, name: &str, kind: &str, help: &str, rules: &[RuleStatus], value: impl Fn(&RuleStatus) -> Option<u64>) {
+    header(out, name, kind, help);
+    for r in rules {
+        if let Some(v) = value(r) {
+            let _ = writeln!(out, "{}{{rule=\"{}\"}} {}", name, escape(&r.name), v);
+        }
+    }
+}
+
+// Label values: backslash, double quote and newline are escaped.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::TrafficStatus;
+
+    fn rule(name: &str, state: RuleState) -> RuleStatus {
+        RuleStatus {
+            name: name.to_string(),
+            description: String::new(),
+            state,
+            since: 0,
+            restarts: 0,
+            auth_failures: 0,
+            backoff_secs: 0,
+            last_connected: None,
+            last_exit: None,
+            traffic: None,
+            recent_output: Vec::new(),
+        }
+    }
+
+    fn report() -> StatusReport {
+        let mut db = rule("db", RuleState::Running);
+        db.restarts = 3;
+        db.last_connected = Some(1_000);
+        db.traffic = Some(TrafficStatus {
+            active_connections: 2,
+            total_connections: 7,
+            bytes_up: 1024,
+            bytes_down: 4096,
+            total_duration_ms: 0,
+            max_duration_ms: 0,
+            rejected_over_limit: 1,
+            throttled: 5,
+        });
+        let mut odd = rule("a\"b\\c\nd", RuleState::Backoff);
+        odd.auth_failures = 1;
+        odd.backoff_secs = 8;
+        odd.last_exit = Some("exited, code=255".to_string());
+        StatusReport {
+            pid: 1,
+            updated: 1_060,
+            rules: vec![db, odd],
+        }
+    }
+
+    #[test]
+    fn samples() {
+        let out = render(&report());
+        let lines: Vec<&str> = out.lines().collect();
+        for sample in [
+            "ssh_tunnel_up{rule=\"db\"} 1",
+            "ssh_tunnel_up{rule=\"a\\\"b\\\\c\\nd\"} 0",
+            "ssh_tunnel_state{rule=\"db\",state=\"running\"} 1",
+            "ssh_tunnel_state{rule=\"db\",state=\"backoff\"} 0",
+            "ssh_tunnel_state{rule=\"a\\\"b\\\\c\\nd\",state=\"backoff\"} 1",
+            "ssh_tunnel_restarts_total{rule=\"db\"} 3",
+            "ssh_tunnel_auth_failures_total{rule=\"a\\\"b\\\\c\\nd\"} 1",
+            "ssh_tunnel_backoff_seconds{rule=\"a\\\"b\\\\c\\nd\"} 8",
+            "ssh_tunnel_seconds_since_connect{rule=\"db\"} 60",
+            "ssh_tunnel_last_exit_info{rule=\"a\\\"b\\\\c\\nd\",reason=\"exited, code=255\"} 1",
+            "ssh_tunnel_connections_active{rule=\"db\"} 2",
+            "ssh_tunnel_connections_total{rule=\"db\"} 7",
+            "ssh_tunnel_connections_rejected_total{rule=\"db\"} 1",
+            "ssh_tunnel_bytes_total{rule=\"db\",direction=\"up\"} 1024",
+            "ssh_tunnel_bytes_total{rule=\"db\",direction=\"down\"} 4096",
+            "ssh_tunnel_throttled_reads_total{rule=\"db\"} 5",
+        ] {
+            assert!(lines.contains(&sample), "missing {:?} in\n{}", sample, out);
+        }
+        // No value, no sample: never connected, no exit yet, not front-proxied.
+        let odd = "rule=\"a\\\"b\\\\c\\nd\"";
+        for family in ["ssh_tunnel_seconds_since_connect", "ssh_tunnel_connections_total", "ssh_tunnel_bytes_total"] {
+            assert!(!lines.iter().any(|l| l.starts_with(family) && l.contains(odd)), "{}", out);
+        }
+        assert!(!lines.iter().any(|l| l.starts_with("ssh_tunnel_last_exit_info{rule=\"db\"")));
+        // One sample per rule and state.
+        assert_eq!(lines.iter().filter(|l| l.starts_with("ssh_tunnel_state{")).count(), 2 * STATES.len());
+    }
+
+    #[test]
+    fn every_family_has_help_and_type() {
+        let out = render(&report());
+        let mut declared: Vec<&str> = Vec::new();
+        let mut lines = out.lines().peekable();
+        while let Some(line) = lines.next() {
+            if let Some(help) = line.strip_prefix("# HELP ") {
+                let name = help.split(' ').next().unwrap();
+                let kind = lines
+                    .next()
+                    .and_then(|l| l.strip_prefix(&format!("# TYPE {} ", name)).map(str::to_string))
+                    .unwrap_or_else(|| panic!("no TYPE after HELP for {}", name));
+                assert!(["gauge", "counter"].contains(&kind.as_str()), "{}", line);
+                assert_eq!(name.ends_with("_total"), kind == "counter", "{}", name);
+                declared.push(name);
+                continue;
+            }
+            // Samples follow the HELP / TYPE of their own family.
+            let name = line.split('{').next().unwrap();
+            assert_eq!(declared.last(), Some(&name), "{}", line);
+        }
+        assert_eq!(declared.len(), 12);
+    }
+}
@@ -0,0 +1,204 @@
+use std::io;
+use std::sync::Mutex;
+
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::config::{Config, ForwardKind, ForwardingRule, PacConfig};
+use crate::http::{self, Request, Response};
+use crate::loopback::LoopbackPool;
+use crate::router::client_host;
+
+// Paths the script is served on; browsers' WPAD lookups use the second.
+const PAC_PATHS: [&str; 2] = ["/proxy.pac", "/wpad.dat"];
+
+// One proxy rule's part of the PAC script (nothing while `domains` is empty).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PacEntry {
+    pub name: String,
+    // PAC proxy string, e.g. "SOCKS5 127.0.0.1:1080; SOCKS 127.0.0.1:1080".
+    pub proxy: String,
+    pub domains: Vec<String>,
+}
+
+// The PAC entry of a proxy (dynamic) rule.
+pub(crate) fn entry(rule: &ForwardingRule) -> Result<Option<PacEntry>, String> {
+    if rule.kind != ForwardKind::Dynamic {
+        if !rule.pac_domains.is_empty() {
+            return Err("pac_domains require kind = \"dynamic\"".to_string());
+        }
+        return Ok(None);
+    }
+    // Patterns are pasted into JavaScript; only allow what a host name can contain.
+    for domain in &rule.pac_domains {
+        let name = domain.strip_prefix("*.").unwrap_or(domain);
+        let valid = !name.is_empty()
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'-' | b'_' | b':'));
+        if !valid {
+            return Err(format!("Invalid pac_domains entry '{}'", domain));
+        }
+    }
+    let host = client_host(&rule.local_bind);
+    let address = if host.contains(':') {
+        format!("[{}]:{}", host, rule.local_port)
+    } else {
+        format!("{}:{}", host, rule.local_port)
+    };
+    let proxy = if rule.http_proxy {
+        format!("PROXY {}", address)
+    } else {
+        format!("SOCKS5 {0}; SOCKS {0}", address)
+    };
+    Ok(Some(PacEntry {
+        name: rule.name(),
+        proxy,
+        domains: rule.pac_domains.iter().map(|d| d.to_ascii_lowercase()).collect(),
+    }))
+}
+
+// FindProxyForURL sending each entry's domains through its rule, everything
+// else DIRECT. The first matching entry wins, in config order.
+pub(crate) fn generate(entries: &[PacEntry]) -> String {
+    let mut script = String::from(
+        "// Generated by ssh-tunnel-manager.\nfunction FindProxyForURL(url, host) {\n    host = host.toLowerCase();\n",
+    );
+    for entry in entries.iter().filter(|e| !e.domains.is_empty()) {
+        let conditions: Vec<String> = entry
+            .domains
+            .iter()
+            .map(|d| match d.strip_prefix("*.") {
+                Some(suffix) => format!("dnsDomainIs(host, \".{}\")", suffix),
+                None => format!("host == \"{}\"", d),
+            })
+            .collect();
+        script.push_str(&format!(
+            "    // {}\n    if ({})\n        return \"{}\";\n",
+            entry.name.replace('\n', " "),
+            conditions.join(" || "),
+            entry.proxy
+        ));
+    }
+    script.push_str("    return \"DIRECT\";\n}\n");
+    script
+}
+
+// The served script, built from the PAC entries of the running proxy rules
+// (in the order they were started).
+pub(crate) struct PacScript {
+    running: Mutex<Vec<PacEntry>>,
+    script: watch::Sender<String>,
+}
+
+impl PacScript {
+    pub fn new() -> Self {
+        Self {
+            running: Mutex::new(Vec::new()),
+            script: watch::Sender::new(generate(&[])),
+        }
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<String> {
+        self.script.subscribe()
+    }
+
+    pub fn add(&self, entry: PacEntry) {
+        let mut running = self.running.lock().unwrap();
+        running.push(entry);
+        self.script.send_replace(generate(&running));
+    }
+
+    pub fn remove(&self, name: &str) {
+        let mut running = self.running.lock().unwrap();
+        running.retain(|e| e.name != name);
+        self.script.send_replace(generate(&running));
+    }
+
+    // Take the pac_domains of the running rules from a re-read config. Only
+    // rules that are running with the same name and proxy address are
+    // updated; anything else needs the rule to be (re)started.
+    pub fn reload(&self, config: &Config) -> Result<(), String> {
+        // auto_loopback rules run on the addresses handed out at startup; hand
+        // them out again, in the same order, to find their running entries.
+        let mut loopback = LoopbackPool::new(&config.loopback, &config.forwarding)?;
+        let mut updates = Vec::new();
+        for rule in &config.forwarding {
+            let mut rule = rule.clone();
+            // A rule without an address did not start either.
+            if rule.auto_loopback && loopback.assign(&mut rule).is_err() {
+                continue;
+            }
+            if let Some(entry) = entry(&rule).map_err(|e| format!("{}: {}", rule.name(), e))? {
+                updates.push(entry);
+            }
+        }
+        let mut running = self.running.lock().unwrap();
+        for entry in updates {
+            match running.iter_mut().find(|r| r.name == entry.name && r.proxy == entry.proxy) {
+                Some(r) => r.domains = entry.domains,
+                None if !entry.domains.is_empty() => {
+                    warn!("PAC reload: rule {} is not running; restart the manager to add it", entry.name)
+                }
+                None => {}
+            }
+        }
+        self.script.send_replace(generate(&running));
+        Ok(())
+    }
+}
+
+// Serve the current script (updated through `script`) on `[pac] listen`.
+pub(crate) async fn run(
+    config: PacConfig,
+    script: watch::Receiver<String>,
+    shutdown: watch::Receiver<bool>,
+) -> io::Result<()> {
+    let listener = http::bind(&config.listen, "pac").await?;
+    info!("Serving PAC file on http://{}{}", config.listen, PAC_PATHS[0]);
+    http::serve(
+        listener,
+        move |request: &Request| match (request.method.as_str(), request.path.as_str()) {
+            ("GET" | "HEAD", path) if PAC_PATHS.contains(&path) => {
+                Response::new("200 OK", "application/x-ns-proxy-autoconfig", script.borrow().clone())
+            }
+            (_, path) if PAC_PATHS.contains(&path) => Response::method_not_allowed(),
+            _ => Response::not_found(),
+        },
+        shutdown,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reload_finds_auto_loopback_rules() {
+        let config = |domains: &str| -> Config {
+            toml::from_str(&format!(
+                r#"
+[[forwarding]]
+kind = "dynamic"
+auto_loopback = true
+local_port = 1080
+pac_domains = [{domains}]
+ssh_host = "bastion"
+ssh_user = "me"
+"#
+            ))
+            .unwrap()
+        };
+        // As started: the rule got the first address of the default range.
+        let mut rule = config(r#""*.corp.internal""#).forwarding.remove(0);
+        rule.local_bind = "127.0.0.2".to_string();
+        let pac = PacScript::new();
+        pac.add(entry(&rule).unwrap().unwrap());
+
+        pac.reload(&config(r#""*.corp.internal", "wiki.example.com""#)).unwrap();
+        let script = pac.subscribe().borrow().clone();
+        assert!(script.contains(r#"host == "wiki.example.com""#), "{script}");
+        assert!(script.contains("SOCKS5 127.0.0.2:1080"), "{script}");
+    }
+}
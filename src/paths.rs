@@ -0,0 +1,82 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+// Per-user directory for sockets and other runtime files:
+// $XDG_RUNTIME_DIR/ssh-tunnel-manager, else <tmp>/ssh-tunnel-manager-<uid>.
+pub(crate) fn runtime_dir() -> io::Result<PathBuf> {
+    let dir = match std::env::var_os("XDG_RUNTIME_DIR").filter(|d| !d.is_empty()) {
+        Some(base) => PathBuf::from(base).join("ssh-tunnel-manager"),
+        None => std::env::temp_dir().join(user_suffixed("ssh-tunnel-manager")),
+    };
+    create_private_dir(&dir)?;
+    Ok(dir)
+}
+
+// Per-user directory for files that outlive a run (audit log, ...):
+// $XDG_STATE_HOME/ssh-tunnel-manager, else ~/.local/state/ssh-tunnel-manager.
+pub(crate) fn state_dir() -> io::Result<PathBuf> {
+    let base = match std::env::var_os("XDG_STATE_HOME").filter(|d| !d.is_empty()) {
+        Some(base) => PathBuf::from(base),
+        None => crate::ssh_args::expand_tilde_path("~/.local/state"),
+    };
+    let dir = base.join("ssh-tunnel-manager");
+    create_private_dir(&dir)?;
+    Ok(dir)
+}
+
+// 64-bit FNV-1a, for the names of runtime files: unlike DefaultHasher, the
+// same on every build and Rust release.
+pub(crate) fn stable_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+#[cfg(unix)]
+fn user_suffixed(name: &str) -> String {
+    // SAFETY: getuid has no preconditions and cannot fail.
+    format!("{}-{}", name, unsafe { libc::getuid() })
+}
+
+#[cfg(not(unix))]
+fn user_suffixed(name: &str) -> String {
+    name.to_string()
+}
+
+#[cfg(unix)]
+fn create_private_dir(dir: &PathBuf) -> io::Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+
+    fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    // A shared /tmp directory could have been created by someone else.
+    let meta = fs::metadata(dir)?;
+    // SAFETY: see user_suffixed.
+    if meta.uid() != unsafe { libc::getuid() } {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is owned by another user", dir.display()),
+        ));
+    }
+    if meta.mode() & 0o077 != 0 {
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn create_private_dir(dir: &PathBuf) -> io::Result<()> {
+    fs::create_dir_all(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stable_hash_is_fnv1a() {
+        assert_eq!(stable_hash(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(stable_hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(stable_hash(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+}
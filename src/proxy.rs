@@ -0,0 +1,819 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::SystemTime;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinSet;
+use tokio::time::{sleep, sleep_until, Duration, Instant};
+use tracing::{info, warn, Instrument};
+
+use crate::acl::{AllowList, HostFilter};
+use crate::audit::{format_timestamp, AuditEntry, AuditLog};
+use crate::config::{ForwardKind, ForwardingRule, Mode};
+use crate::http_proxy;
+use crate::limit::TokenBucket;
+use crate::status::{RecentOutput, RuleHandle, RuleState, Traffic};
+use crate::supervisor::{rule_subject, supervise_subject};
+use crate::transport::{BoxFuture, ExitReason, Transport, TransportFactory};
+
+// Interval between attempts to reach the tunnel's internal port.
+const CONNECT_RETRY: Duration = Duration::from_millis(200);
+
+// Whether the manager listens on the rule's port itself, with the ssh forward
+// on an internal loopback port behind it.
+pub(crate) fn uses_front_proxy(rule: &ForwardingRule) -> bool {
+    rule.mode == Mode::OnDemand
+        || rule.front_proxy
+        || !rule.allow_from.is_empty()
+        || rule.max_connections.is_some()
+        || rule.rate_limit_up.is_some()
+        || rule.rate_limit_down.is_some()
+        || rule.audit_log
+        || rule.http_proxy
+}
+
+// The rule the transport runs behind the front proxy: the same tunnel, but
+// listening on a loopback port that only the manager connects to (allocated
+// by `InternalTunnel` on every start).
+pub(crate) fn internal_rule(rule: &ForwardingRule) -> Result<ForwardingRule, String> {
+    if rule.kind == ForwardKind::Remote {
+        return Err(
+            "front_proxy, allow_from, limits, audit_log and mode = \"on_demand\" require kind = \"local\" or \"dynamic\""
+                .to_string(),
+        );
+    }
+    if rule.http_proxy && rule.kind != ForwardKind::Dynamic {
+        return Err("http_proxy requires kind = \"dynamic\"".to_string());
+    }
+    if rule.rate_limit_up == Some(0) || rule.rate_limit_down == Some(0) {
+        return Err("rate_limit_up / rate_limit_down must be greater than 0".to_string());
+    }
+    // Reject bad entries at startup rather than on the first connection.
+    AllowList::parse(&rule.allow_from)?;
+    let mut internal = rule.clone();
+    internal.local_bind = "127.0.0.1".to_string();
+    internal.local_port = 0;
+    Ok(internal)
+}
+
+// Transport of a front-proxied rule. Every start moves the tunnel to a newly
+// allocated loopback port, so a port taken by someone else since it was
+// picked costs one attempt rather than every restart; the relay follows
+// `port`. Local users can still connect to that port directly, past
+// allow_from, the limits and the audit log (see README).
+pub(crate) struct InternalTunnel {
+    rule: ForwardingRule,
+    build: TransportFactory,
+    current: Option<Box<dyn Transport>>,
+    port: Arc<AtomicU16>,
+    output: Option<Arc<RecentOutput>>,
+}
+
+impl InternalTunnel {
+    pub(crate) fn new(rule: ForwardingRule, mut build: TransportFactory) -> Result<Self, String> {
+        // Validate the rule now rather than on the first start.
+        build(&rule)?;
+        Ok(Self {
+            rule,
+            build,
+            current: None,
+            port: Arc::new(AtomicU16::new(0)),
+            output: None,
+        })
+    }
+
+    // The port of the current session (0 before the first start).
+    pub(crate) fn port(&self) -> Arc<AtomicU16> {
+        self.port.clone()
+    }
+}
+
+impl Transport for InternalTunnel {
+    fn start(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            let port = std::net::TcpListener::bind(("127.0.0.1", 0))
+                .and_then(|l| l.local_addr())
+                .map_err(|e| io::Error::other(format!("allocate internal port: {}", e)))?
+                .port();
+            let mut rule = self.rule.clone();
+            rule.local_port = port;
+            let mut transport = (self.build)(&rule).map_err(io::Error::other)?;
+            if let Some(output) = &self.output {
+                transport.record_output(output.clone());
+            }
+            self.port.store(port, Ordering::Relaxed);
+            transport.start().await?;
+            self.current = Some(transport);
+            Ok(())
+        })
+    }
+
+    fn ready(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            match &mut self.current {
+                Some(transport) => transport.ready().await,
+                None => Err(io::Error::other("not started")),
+            }
+        })
+    }
+
+    fn wait(&mut self) -> BoxFuture<'_, ExitReason> {
+        Box::pin(async move {
+            match &mut self.current {
+                Some(transport) => transport.wait().await,
+                None => ExitReason::Error("not started".to_string()),
+            }
+        })
+    }
+
+    fn kill(&mut self) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            if let Some(transport) = &mut self.current {
+                transport.kill().await;
+            }
+        })
+    }
+
+    fn record_output(&mut self, output: Arc<RecentOutput>) {
+        self.output = Some(output);
+    }
+}
+
+// Client-side half of a front-proxied rule, shared by its connection tasks.
+struct Relay {
+    name: String,
+    internal_port: Arc<AtomicU16>,
+    queue_timeout: Duration,
+    allow: AllowList,
+    // Set for `http_proxy` rules: clients speak HTTP proxy, the tunnel SOCKS.
+    http_proxy: Option<HostFilter>,
+    max_connections: Option<u64>,
+    limit_up: Option<TokenBucket>,
+    limit_down: Option<TokenBucket>,
+    status: Arc<RuleHandle>,
+    audit: Option<Arc<AuditLog>>,
+}
+
+impl Relay {
+    fn traffic(&self) -> &Traffic {
+        self.status.traffic.as_ref().expect("front-proxied rules track traffic")
+    }
+
+    // Relay one client connection through the tunnel. While the tunnel is down
+    // (connecting, or in backoff) the connection waits up to queue_timeout.
+    async fn splice(self: Arc<Self>, Client { stream: client, peer, head }: Client) {
+        let mut conn = Connection::new(&self, peer);
+        if !self.allow.allows(peer.ip()) {
+            warn!("Rejected connection from {} to {}: not in allow_from", peer, self.name);
+            conn.close("rejected: allow_from");
+            return;
+        }
+        let traffic = self.traffic();
+        // Reserve a slot; queued connections count, so the limit also bounds the queue.
+        let max = self.max_connections.unwrap_or(u64::MAX);
+        if traffic
+            .active
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| (n < max).then_some(n + 1))
+            .is_err()
+        {
+            traffic.rejected_over_limit.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                "Rejected connection from {} to {}: max_connections ({}) reached",
+                peer, self.name, max
+            );
+            conn.close("rejected: max_connections");
+            return;
+        }
+        traffic.total.fetch_add(1, Ordering::Relaxed);
+        conn.counted = true;
+        self.relay(client, head, &conn).await;
+    }
+
+    async fn relay(&self, mut client: TcpStream, head: Vec<u8>, conn: &Connection<'_>) {
+        let request = match &self.http_proxy {
+            Some(filter) => match http_proxy::read_request(&mut client).await {
+                Ok(request) => {
+                    let _ = conn.target.set(request.target());
+                    if !http_proxy::check_host(&request, filter, &mut client).await {
+                        warn!(
+                            "HTTP proxy {}: rejected {} -> {}: not permitted by allow_hosts / deny_hosts",
+                            self.name,
+                            conn.peer,
+                            request.target()
+                        );
+                        conn.close("rejected: host");
+                        return;
+                    }
+                    Some(request)
+                }
+                Err(reason) => {
+                    warn!("HTTP proxy {}: request from {}: {}", self.name, conn.peer, reason);
+                    conn.close(&reason);
+                    return;
+                }
+            },
+            None => None,
+        };
+        let deadline = Instant::now() + self.queue_timeout;
+        let mut upstream = loop {
+            match TcpStream::connect(("127.0.0.1", self.internal_port.load(Ordering::Relaxed))).await {
+                Ok(s) => break s,
+                Err(_) if Instant::now() < deadline => sleep(CONNECT_RETRY).await,
+                Err(e) => {
+                    warn!(
+                        "Dropping connection from {}: tunnel not ready after {:?} ({})",
+                        conn.peer, self.queue_timeout, e
+                    );
+                    if request.is_some() {
+                        http_proxy::reply_error(&mut client, "504 Gateway Timeout", "tunnel not ready").await;
+                    }
+                    conn.close("tunnel_not_ready");
+                    return;
+                }
+            }
+        };
+        let traffic = self.traffic();
+        // What the router read from the client to find the rule.
+        if !head.is_empty() {
+            if let Err(e) = upstream.write_all(&head).await {
+                conn.close(&format!("error: {}", e));
+                return;
+            }
+            conn.bytes_up.fetch_add(head.len() as u64, Ordering::Relaxed);
+            traffic.bytes_up.fetch_add(head.len() as u64, Ordering::Relaxed);
+        }
+        if let Some(request) = &request {
+            match http_proxy::open(request, &mut client, &mut upstream).await {
+                Ok(sent) => {
+                    conn.bytes_up.fetch_add(sent, Ordering::Relaxed);
+                    traffic.bytes_up.fetch_add(sent, Ordering::Relaxed);
+                }
+                Err(reason) => {
+                    warn!("HTTP proxy {}: {} -> {}: {}", self.name, conn.peer, request.target(), reason);
+                    conn.close(&reason);
+                    return;
+                }
+            }
+        }
+        let (client_rx, client_tx) = client.into_split();
+        let (upstream_rx, upstream_tx) = upstream.into_split();
+        let up = Pump {
+            counters: [&conn.bytes_up, &traffic.bytes_up],
+            limit: self.limit_up.as_ref(),
+            throttled: &traffic.throttled,
+            eof_reason: "client_closed",
+            close_reason: &conn.reason,
+        };
+        let down = Pump {
+            counters: [&conn.bytes_down, &traffic.bytes_down],
+            limit: self.limit_down.as_ref(),
+            throttled: &traffic.throttled,
+            eof_reason: "remote_closed",
+            close_reason: &conn.reason,
+        };
+        // Like copy_bidirectional, but counting as it goes so status is live.
+        if let Err(e) = tokio::try_join!(up.run(client_rx, upstream_tx), down.run(upstream_rx, client_tx)) {
+            conn.close(&format!("error: {}", e));
+        }
+    }
+}
+
+// One client connection. Dropping it (normally, or when the task is aborted on
+// shutdown) settles the rule's counters and writes the audit entry.
+struct Connection<'a> {
+    relay: &'a Relay,
+    peer: SocketAddr,
+    started_at: SystemTime,
+    started: Instant,
+    bytes_up: AtomicU64,
+    bytes_down: AtomicU64,
+    // host:port requested through the HTTP proxy.
+    target: OnceLock<String>,
+    // First reason wins: whichever side closed, or the error.
+    reason: OnceLock<String>,
+    // Holds a slot in the rule's active connections.
+    counted: bool,
+}
+
+impl<'a> Connection<'a> {
+    fn new(relay: &'a Relay, peer: SocketAddr) -> Self {
+        Self {
+            relay,
+            peer,
+            started_at: SystemTime::now(),
+            started: Instant::now(),
+            bytes_up: AtomicU64::new(0),
+            bytes_down: AtomicU64::new(0),
+            target: OnceLock::new(),
+            reason: OnceLock::new(),
+            counted: false,
+        }
+    }
+
+    fn close(&self, reason: &str) {
+        let _ = self.reason.set(reason.to_string());
+    }
+}
+
+impl Drop for Connection<'_> {
+    fn drop(&mut self) {
+        let duration = self.started.elapsed();
+        if self.counted {
+            self.relay.traffic().connection_closed(duration);
+        }
+        if let Some(audit) = &self.relay.audit {
+            audit.record(&AuditEntry {
+                rule: &self.relay.name,
+                client: self.peer.to_string(),
+                target: self.target.get().map(String::as_str),
+                start: format_timestamp(self.started_at),
+                end: format_timestamp(SystemTime::now()),
+                duration_ms: duration.as_millis() as u64,
+                bytes_up: self.bytes_up.load(Ordering::Relaxed),
+                bytes_down: self.bytes_down.load(Ordering::Relaxed),
+                close_reason: self.reason.get().map(String::as_str).unwrap_or("shutdown"),
+            });
+        }
+    }
+}
+
+// One direction of a relayed connection.
+struct Pump<'a> {
+    // Per-connection and per-rule byte counters.
+    counters: [&'a AtomicU64; 2],
+    limit: Option<&'a TokenBucket>,
+    throttled: &'a AtomicU64,
+    // Close reason when this side's reader hits EOF first.
+    eof_reason: &'static str,
+    close_reason: &'a OnceLock<String>,
+}
+
+impl Pump<'_> {
+    // Copy until EOF, then half-close the writer. With a rate limit, each chunk
+    // is paid for before it is written.
+    async fn run<R, W>(&self, mut reader: R, mut writer: W) -> io::Result<()>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut buf = vec![0u8; 16 * 1024];
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                let _ = self.close_reason.set(self.eof_reason.to_string());
+                return writer.shutdown().await;
+            }
+            if let Some(bucket) = self.limit {
+                if bucket.take(n).await {
+                    self.throttled.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            writer.write_all(&buf[..n]).await?;
+            for counter in self.counters {
+                counter.fetch_add(n as u64, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+// A client connection of a front-proxied rule.
+pub(crate) struct Client {
+    pub stream: TcpStream,
+    pub peer: SocketAddr,
+    // Already read from the client (by the router); sent ahead of the rest.
+    pub head: Vec<u8>,
+}
+
+// Where a front-proxied rule's clients come from: its own port, and the router
+// for rules with `hostnames`.
+struct Clients {
+    listener: TcpListener,
+    routed: Option<mpsc::Receiver<Client>>,
+}
+
+impl Clients {
+    // Cancel-safe, like the accept and recv it waits on.
+    async fn next(&mut self) -> io::Result<Client> {
+        let routed = async {
+            match &mut self.routed {
+                Some(routed) => routed.recv().await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            res = self.listener.accept() => res.map(|(stream, peer)| Client { stream, peer, head: Vec::new() }),
+            Some(client) = routed => Ok(client),
+        }
+    }
+}
+
+// Serve a front-proxied rule: the manager owns local_bind:local_port and relays
+// to the transport's internal port. `routed` brings the clients the router
+// accepted for the rule.
+pub(crate) async fn run(
+    rule: ForwardingRule,
+    internal_port: Arc<AtomicU16>,
+    transport: Box<dyn Transport>,
+    status: Arc<RuleHandle>,
+    audit: Option<Arc<AuditLog>>,
+    routed: Option<mpsc::Receiver<Client>>,
+    shutdown: watch::Receiver<bool>,
+) -> io::Result<()> {
+    let listener = TcpListener::bind((rule.local_bind.as_str(), rule.local_port)).await?;
+    let clients = Clients { listener, routed };
+    let allow = AllowList::parse(&rule.allow_from).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let relay = Arc::new(Relay {
+        name: rule.name(),
+        internal_port,
+        queue_timeout: Duration::from_secs(rule.queue_timeout),
+        allow,
+        http_proxy: rule
+            .http_proxy
+            .then(|| HostFilter::new(&rule.allow_hosts, &rule.deny_hosts)),
+        max_connections: rule.max_connections,
+        limit_up: rule.rate_limit_up.map(TokenBucket::new),
+        limit_down: rule.rate_limit_down.map(TokenBucket::new),
+        status,
+        audit: if rule.audit_log { audit } else { None },
+    });
+    match rule.mode {
+        Mode::Persistent => run_persistent(rule, clients, relay, transport, shutdown).await,
+        Mode::OnDemand => run_on_demand(rule, clients, relay, transport, shutdown).await,
+    }
+}
+
+// Tunnel always supervised; the listener stays open through reconnects.
+async fn run_persistent(
+    rule: ForwardingRule,
+    mut clients: Clients,
+    relay: Arc<Relay>,
+    mut transport: Box<dyn Transport>,
+    shutdown: watch::Receiver<bool>,
+) -> io::Result<()> {
+    let subject = rule_subject(&rule, Some(relay.status.clone()));
+    info!("Listening on {}:{} (front proxy)", rule.local_bind, rule.local_port);
+
+    let tunnel = supervise_subject(&subject, transport.as_mut(), shutdown);
+    tokio::pin!(tunnel);
+    let mut conns = JoinSet::new();
+    loop {
+        tokio::select! {
+            // Supervisor finished (shutdown, or auth failure): close the port.
+            res = &mut tunnel => return res,
+            res = clients.next() => match res {
+                Ok(client) => {
+                    conns.spawn(relay.clone().splice(client).in_current_span());
+                }
+                // e.g. EMFILE or a connection reset before it was accepted; keep serving.
+                Err(e) => warn!("accept on {}:{} failed: {}", rule.local_bind, rule.local_port, e),
+            },
+            Some(_) = conns.join_next() => {}
+        }
+    }
+}
+
+// Listen on local_bind:local_port, start the tunnel on the first connection,
+// stop it after idle_timeout without connections.
+async fn run_on_demand(
+    rule: ForwardingRule,
+    mut clients: Clients,
+    relay: Arc<Relay>,
+    mut transport: Box<dyn Transport>,
+    mut shutdown: watch::Receiver<bool>,
+) -> io::Result<()> {
+    let subject = rule_subject(&rule, Some(relay.status.clone()));
+    let idle_timeout = Duration::from_secs(rule.idle_timeout);
+    info!("Listening on {}:{} (on demand)", rule.local_bind, rule.local_port);
+
+    loop {
+        relay.status.set_state(RuleState::Idle);
+        // Idle: no tunnel until someone connects.
+        let first = tokio::select! {
+            res = clients.next() => match res {
+                Ok(client) => client,
+                Err(e) => {
+                    warn!("accept on {}:{} failed: {}", rule.local_bind, rule.local_port, e);
+                    continue;
+                }
+            },
+            _ = shutdown.changed() => {
+                relay.status.set_state(RuleState::Stopped);
+                return Ok(());
+            }
+        };
+        info!("Connection on {}:{}; starting tunnel", rule.local_bind, rule.local_port);
+
+        let (stop_tx, stop_rx) = watch::channel(false);
+        let tunnel = supervise_subject(&subject, transport.as_mut(), stop_rx);
+        tokio::pin!(tunnel);
+        let mut conns = JoinSet::new();
+        conns.spawn(relay.clone().splice(first).in_current_span());
+        let mut idle_since = Instant::now();
+
+        let stopped_by_tunnel = loop {
+            tokio::select! {
+                // Supervisor gave up (auth failure): stop serving this rule.
+                res = &mut tunnel => {
+                    res?;
+                    break true;
+                }
+                res = clients.next() => match res {
+                    Ok(client) => {
+                        conns.spawn(relay.clone().splice(client).in_current_span());
+                    }
+                    Err(e) => warn!("accept on {}:{} failed: {}", rule.local_bind, rule.local_port, e),
+                },
+                Some(_) = conns.join_next() => {
+                    if conns.is_empty() {
+                        idle_since = Instant::now();
+                    }
+                }
+                _ = sleep_until(idle_since + idle_timeout), if conns.is_empty() => {
+                    info!(
+                        "No connections on {}:{} for {:?}; stopping tunnel",
+                        rule.local_bind, rule.local_port, idle_timeout
+                    );
+                    break false;
+                }
+                _ = shutdown.changed() => {
+                    let _ = stop_tx.send(true);
+                    let _ = (&mut tunnel).await;
+                    return Ok(());
+                }
+            }
+        };
+        if stopped_by_tunnel {
+            return Ok(());
+        }
+        let _ = stop_tx.send(true);
+        tunnel.await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::{RuleStatus, StatusBoard};
+    use std::sync::atomic::AtomicU32;
+    use tokio::sync::broadcast;
+
+    // Stands in for the tunnel's internal port: echoes every connection.
+    async fn echo_server() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut rx, mut tx) = stream.split();
+                    let _ = tokio::io::copy(&mut rx, &mut tx).await;
+                });
+            }
+        });
+        port
+    }
+
+    fn relay(internal_port: Arc<AtomicU16>, max_connections: Option<u64>) -> Arc<Relay> {
+        let board = StatusBoard::new(broadcast::channel(16).0);
+        Arc::new(Relay {
+            name: "db".to_string(),
+            internal_port,
+            queue_timeout: Duration::from_secs(5),
+            allow: AllowList::parse(&[]).unwrap(),
+            http_proxy: None,
+            max_connections,
+            limit_up: None,
+            limit_down: None,
+            status: board.register("db".to_string(), String::new(), true),
+            audit: None,
+        })
+    }
+
+    // A connected pair: the test's end, and the relay's accepted end.
+    async fn connect() -> (TcpStream, Client) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ours = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (stream, peer) = listener.accept().await.unwrap();
+        (ours, Client { stream, peer, head: Vec::new() })
+    }
+
+    async fn echoes(stream: &mut TcpStream, msg: &[u8]) -> bool {
+        let mut buf = vec![0u8; msg.len()];
+        stream.write_all(msg).await.is_ok() && stream.read_exact(&mut buf).await.is_ok() && buf == msg
+    }
+
+    #[tokio::test]
+    async fn max_connections_refuses_the_next_one() {
+        let relay = relay(Arc::new(AtomicU16::new(echo_server().await)), Some(1));
+        let (mut first, client) = connect().await;
+        let served = tokio::spawn(relay.clone().splice(client));
+        assert!(echoes(&mut first, b"one").await);
+
+        // The slot is taken: the second client is closed without being relayed.
+        let (mut second, client) = connect().await;
+        relay.clone().splice(client).await;
+        assert_eq!(second.read(&mut [0u8; 16]).await.unwrap(), 0);
+        let traffic = relay.traffic();
+        assert_eq!(traffic.rejected_over_limit.load(Ordering::Relaxed), 1);
+        assert_eq!(traffic.active.load(Ordering::Relaxed), 1);
+        assert_eq!(traffic.total.load(Ordering::Relaxed), 1);
+
+        // Closing the first frees the slot.
+        drop(first);
+        served.await.unwrap();
+        assert_eq!(traffic.active.load(Ordering::Relaxed), 0);
+        let (mut third, client) = connect().await;
+        tokio::spawn(relay.clone().splice(client));
+        assert!(echoes(&mut third, b"three").await);
+        assert_eq!(traffic.total.load(Ordering::Relaxed), 2);
+    }
+
+    // A tunnel whose internal port echoes, `delay` after each start.
+    struct Tunnel {
+        port: Arc<AtomicU16>,
+        delay: Duration,
+        starts: Arc<AtomicU32>,
+        kills: Arc<AtomicU32>,
+    }
+
+    impl Transport for Tunnel {
+        fn start(&mut self) -> BoxFuture<'_, io::Result<()>> {
+            self.starts.fetch_add(1, Ordering::Relaxed);
+            Box::pin(async { Ok(()) })
+        }
+
+        fn ready(&mut self) -> BoxFuture<'_, io::Result<()>> {
+            Box::pin(async {
+                sleep(self.delay).await;
+                self.port.store(echo_server().await, Ordering::Relaxed);
+                Ok(())
+            })
+        }
+
+        fn wait(&mut self) -> BoxFuture<'_, ExitReason> {
+            Box::pin(std::future::pending())
+        }
+
+        fn kill(&mut self) -> BoxFuture<'_, ()> {
+            self.kills.fetch_add(1, Ordering::Relaxed);
+            self.port.store(0, Ordering::Relaxed);
+            Box::pin(async {})
+        }
+    }
+
+    // An on-demand rule served on a local port, with its tunnel's counters.
+    struct OnDemand {
+        addr: SocketAddr,
+        relay: Arc<Relay>,
+        starts: Arc<AtomicU32>,
+        kills: Arc<AtomicU32>,
+        shutdown: watch::Sender<bool>,
+        served: tokio::task::JoinHandle<io::Result<()>>,
+    }
+
+    impl OnDemand {
+        async fn start(delay: Duration, queue_timeout: u64) -> Self {
+            let rule: ForwardingRule = toml::from_str(&format!(
+                r#"
+name = "db"
+local_port = 15432
+remote_address = "db.internal:5432"
+ssh_host = "bastion.example.com"
+ssh_user = "tester"
+mode = "on_demand"
+idle_timeout = 60
+queue_timeout = {queue_timeout}
+"#
+            ))
+            .unwrap();
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let port = Arc::new(AtomicU16::new(0));
+            let mut relay = relay(port.clone(), None);
+            Arc::get_mut(&mut relay).unwrap().queue_timeout = Duration::from_secs(queue_timeout);
+            let (starts, kills) = (Arc::new(AtomicU32::new(0)), Arc::new(AtomicU32::new(0)));
+            let tunnel = Tunnel {
+                port,
+                delay,
+                starts: starts.clone(),
+                kills: kills.clone(),
+            };
+            let (shutdown, shutdown_rx) = watch::channel(false);
+            let clients = Clients { listener, routed: None };
+            let served = tokio::spawn(run_on_demand(rule, clients, relay.clone(), Box::new(tunnel), shutdown_rx));
+            Self {
+                addr,
+                relay,
+                starts,
+                kills,
+                shutdown,
+                served,
+            }
+        }
+
+        fn status(&self) -> RuleStatus {
+            self.relay.status.snapshot()
+        }
+
+        fn counts(&self) -> (u32, u32) {
+            (self.starts.load(Ordering::Relaxed), self.kills.load(Ordering::Relaxed))
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn on_demand_starts_on_first_connection_and_stops_when_idle() {
+        let mut rule = OnDemand::start(Duration::ZERO, 5).await;
+        sleep(Duration::from_secs(600)).await;
+        assert_eq!(rule.counts(), (0, 0));
+        assert_eq!(rule.status().state, RuleState::Idle);
+
+        let mut client = TcpStream::connect(rule.addr).await.unwrap();
+        assert!(echoes(&mut client, b"ping").await);
+        assert_eq!(rule.counts(), (1, 0));
+        assert_eq!(rule.status().state, RuleState::Running);
+
+        // An open connection keeps the tunnel up past idle_timeout.
+        sleep(Duration::from_secs(120)).await;
+        assert_eq!(rule.counts(), (1, 0));
+
+        // Counted from the last connection's close.
+        drop(client);
+        sleep(Duration::from_secs(59)).await;
+        assert_eq!(rule.counts(), (1, 0));
+        sleep(Duration::from_secs(2)).await;
+        assert_eq!(rule.counts(), (1, 1));
+        assert_eq!(rule.status().state, RuleState::Idle);
+
+        // And the next connection starts it again.
+        let mut client = TcpStream::connect(rule.addr).await.unwrap();
+        assert!(echoes(&mut client, b"again").await);
+        assert_eq!(rule.counts(), (2, 1));
+
+        rule.shutdown.send(true).unwrap();
+        (&mut rule.served).await.unwrap().unwrap();
+        assert_eq!(rule.counts(), (2, 2));
+        assert_eq!(rule.status().state, RuleState::Stopped);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connections_queue_while_the_tunnel_starts() {
+        let rule = OnDemand::start(Duration::from_secs(3), 5).await;
+        let start = Instant::now();
+        let mut first = TcpStream::connect(rule.addr).await.unwrap();
+        let mut second = TcpStream::connect(rule.addr).await.unwrap();
+        assert!(echoes(&mut first, b"one").await);
+        assert!(echoes(&mut second, b"two").await);
+        // Relayed on the first retry after the tunnel came up.
+        let waited = start.elapsed();
+        assert!(waited >= Duration::from_secs(3) && waited <= Duration::from_millis(3200), "{:?}", waited);
+        assert_eq!(rule.counts(), (1, 0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn queued_connections_give_up_after_queue_timeout() {
+        let rule = OnDemand::start(Duration::from_secs(30), 5).await;
+        let start = Instant::now();
+        let mut client = TcpStream::connect(rule.addr).await.unwrap();
+        assert_eq!(client.read(&mut [0u8; 16]).await.unwrap(), 0);
+        let waited = start.elapsed();
+        assert!(waited >= Duration::from_secs(5) && waited <= Duration::from_millis(5200), "{:?}", waited);
+        let traffic = rule.status().traffic.unwrap();
+        assert_eq!((traffic.total_connections, traffic.active_connections, traffic.bytes_up), (1, 0, 0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn traffic_is_counted() {
+        let rule = OnDemand::start(Duration::ZERO, 5).await;
+        let mut client = TcpStream::connect(rule.addr).await.unwrap();
+        assert!(echoes(&mut client, b"ping").await);
+        assert!(echoes(&mut client, &[7u8; 40_000]).await);
+        let traffic = rule.status().traffic.unwrap();
+        assert_eq!((traffic.active_connections, traffic.total_connections), (1, 1));
+        assert_eq!((traffic.bytes_up, traffic.bytes_down), (40_004, 40_004));
+
+        sleep(Duration::from_secs(2)).await;
+        drop(client);
+        sleep(Duration::from_millis(10)).await;
+        let traffic = rule.status().traffic.unwrap();
+        assert_eq!((traffic.active_connections, traffic.total_connections), (0, 1));
+        assert!(traffic.max_duration_ms >= 2000, "{:?}", traffic.max_duration_ms);
+        assert_eq!(traffic.total_duration_ms, traffic.max_duration_ms);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn routed_head_is_sent_first_and_counted() {
+        let relay = relay(Arc::new(AtomicU16::new(echo_server().await)), None);
+        let (mut ours, mut client) = connect().await;
+        client.head = b"GET / HTTP/1.1\r\n".to_vec();
+        tokio::spawn(relay.clone().splice(client));
+        let mut buf = [0u8; 16];
+        ours.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"GET / HTTP/1.1\r\n");
+        assert!(echoes(&mut ours, b"Host: a\r\n\r\n").await);
+        assert_eq!(relay.traffic().bytes_up.load(Ordering::Relaxed), 27);
+        assert_eq!(relay.traffic().bytes_down.load(Ordering::Relaxed), 27);
+    }
+}
@@ -0,0 +1,494 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use tokio::io::{copy_bidirectional, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout, Duration, Instant};
+use tracing::{info, warn};
+
+use crate::acl::{host_matches, AllowList};
+use crate::config::{ForwardKind, ForwardingRule, RouterConfig};
+use crate::proxy::Client;
+
+// How long a client may take to send its ClientHello / request headers.
+const PEEK_TIMEOUT: Duration = Duration::from_secs(10);
+// Enough for any real ClientHello or request head.
+const PEEK_LIMIT: usize = 16 * 1024;
+// Interval between attempts to reach a rule that is reconnecting.
+const CONNECT_RETRY: Duration = Duration::from_millis(200);
+
+// Hostnames served by one rule, and where that rule listens.
+struct Route {
+    name: String,
+    patterns: Vec<String>,
+    // Clients that could connect to the rule directly.
+    allow: AllowList,
+    target: Target,
+    queue_timeout: Duration,
+}
+
+enum Target {
+    // The ssh forward's own listener.
+    Listener { host: String, port: u16 },
+    // The rule's front proxy, which takes the client as it is, so allow_from,
+    // the limits and the audit log see its real address.
+    Proxy(mpsc::Sender<Client>),
+}
+
+impl Route {
+    fn matches(&self, hostname: &str) -> bool {
+        self.patterns.iter().any(|p| host_matches(p, hostname))
+    }
+}
+
+// Routes for the rules that declare `hostnames`.
+pub(crate) struct Routes {
+    routes: Vec<Route>,
+}
+
+impl Routes {
+    pub fn new() -> Self {
+        Self { routes: Vec::new() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    // Whether `add` would accept the rule.
+    pub fn check(&self, rule: &ForwardingRule) -> Result<(), String> {
+        if rule.hostnames.is_empty() {
+            return Ok(());
+        }
+        if rule.kind != ForwardKind::Local {
+            return Err("hostnames require kind = \"local\"".to_string());
+        }
+        for pattern in &rule.hostnames {
+            if let Some(other) = self.routes.iter().find(|r| r.patterns.contains(pattern)) {
+                return Err(format!("hostname '{}' is already routed to {}", pattern, other.name));
+            }
+        }
+        Ok(())
+    }
+
+    // Route the rule's hostnames to it: to `proxy` for front-proxied rules,
+    // otherwise to the rule's listener.
+    pub fn add(&mut self, rule: &ForwardingRule, proxy: Option<mpsc::Sender<Client>>) -> Result<(), String> {
+        self.check(rule)?;
+        if rule.hostnames.is_empty() {
+            return Ok(());
+        }
+        // The router may listen more widely than the rule, e.g. on 0.0.0.0 for a
+        // rule on 127.0.0.1; it admits only the clients the rule itself would.
+        let allow = if !rule.allow_from.is_empty() {
+            AllowList::parse(&rule.allow_from)?
+        } else if is_loopback(&rule.local_bind) {
+            AllowList::loopback()
+        } else {
+            AllowList::default()
+        };
+        let target = match proxy {
+            Some(proxy) => Target::Proxy(proxy),
+            None => Target::Listener {
+                host: client_host(&rule.local_bind),
+                port: rule.local_port,
+            },
+        };
+        self.routes.push(Route {
+            name: rule.name(),
+            patterns: rule.hostnames.clone(),
+            allow,
+            target,
+            queue_timeout: Duration::from_secs(rule.queue_timeout),
+        });
+        Ok(())
+    }
+
+    fn find(&self, hostname: &str) -> Option<&Route> {
+        self.routes.iter().find(|r| r.matches(hostname))
+    }
+}
+
+impl Default for Routes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Address a local client uses to reach a listener bound to `bind`.
+pub(crate) fn client_host(bind: &str) -> String {
+    match bind {
+        "0.0.0.0" => "127.0.0.1".to_string(),
+        "::" | "[::]" => "::1".to_string(),
+        bind => bind.to_string(),
+    }
+}
+
+fn is_loopback(bind: &str) -> bool {
+    bind == "localhost"
+        || bind
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback())
+}
+
+// Accept on every `[router] listen` address and dispatch by SNI / Host header.
+pub(crate) async fn run(config: RouterConfig, routes: Routes, mut shutdown: watch::Receiver<bool>) -> io::Result<()> {
+    let routes = Arc::new(routes);
+    let mut listeners = Vec::new();
+    for addr in &config.listen {
+        let listener = TcpListener::bind(addr.as_str()).await.map_err(|e| {
+            io::Error::new(e.kind(), format!("router listen {}: {}", addr, e))
+        })?;
+        info!("Router listening on {}", addr);
+        listeners.push(listener);
+    }
+
+    let mut accepts = JoinSet::new();
+    for listener in listeners {
+        let routes = routes.clone();
+        accepts.spawn(async move {
+            let mut conns = JoinSet::new();
+            loop {
+                tokio::select! {
+                    res = listener.accept() => match res {
+                        Ok(conn) => {
+                            conns.spawn(dispatch(routes.clone(), conn));
+                        }
+                        Err(e) => warn!("router accept failed: {}", e),
+                    },
+                    Some(_) = conns.join_next() => {}
+                }
+            }
+        });
+    }
+    let _ = shutdown.changed().await;
+    Ok(())
+}
+
+async fn dispatch(routes: Arc<Routes>, (mut client, peer): (TcpStream, SocketAddr)) {
+    let (buf, hostname) = match timeout(PEEK_TIMEOUT, peek_hostname(&mut client)).await {
+        Ok(Ok(res)) => res,
+        Ok(Err(e)) => {
+            warn!("router: {}: {}", peer, e);
+            return;
+        }
+        Err(_) => {
+            warn!("router: {}: no TLS ClientHello / HTTP request within {:?}", peer, PEEK_TIMEOUT);
+            return;
+        }
+    };
+    let Some(route) = routes.find(&hostname) else {
+        warn!("router: {}: no rule for host '{}'", peer, hostname);
+        return;
+    };
+    if !route.allow.allows(peer.ip()) {
+        warn!("router: rejected connection from {} to {}: not allowed to reach the rule", peer, route.name);
+        return;
+    }
+
+    let (host, port) = match &route.target {
+        Target::Proxy(proxy) => {
+            let client = Client {
+                stream: client,
+                peer,
+                head: buf,
+            };
+            if proxy.send(client).await.is_err() {
+                warn!("router: {}: rule {} is not running", peer, route.name);
+            }
+            return;
+        }
+        Target::Listener { host, port } => (host, *port),
+    };
+    let deadline = Instant::now() + route.queue_timeout;
+    let mut upstream = loop {
+        match TcpStream::connect((host.as_str(), port)).await {
+            Ok(s) => break s,
+            Err(_) if Instant::now() < deadline => sleep(CONNECT_RETRY).await,
+            Err(e) => {
+                warn!("router: {}: rule {} not reachable: {}", peer, route.name, e);
+                return;
+            }
+        }
+    };
+    // Replay what was read while looking for the hostname, then splice.
+    if upstream.write_all(&buf).await.is_err() {
+        return;
+    }
+    let _ = copy_bidirectional(&mut client, &mut upstream).await;
+}
+
+#[derive(Debug, PartialEq)]
+enum Peek {
+    Found(String),
+    Incomplete,
+    Invalid(&'static str),
+}
+
+// Read from the client until its hostname is known; returns the bytes read too.
+async fn peek_hostname(client: &mut TcpStream) -> io::Result<(Vec<u8>, String)> {
+    let mut buf = Vec::with_capacity(4096);
+    loop {
+        let res = if buf.first() == Some(&0x16) {
+            parse_client_hello(&buf)
+        } else {
+            parse_http_host(&buf)
+        };
+        match res {
+            Peek::Found(hostname) => return Ok((buf, hostname)),
+            Peek::Invalid(why) => return Err(io::Error::new(io::ErrorKind::InvalidData, why)),
+            Peek::Incomplete if buf.len() >= PEEK_LIMIT => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "no hostname in first 16 KiB"));
+            }
+            Peek::Incomplete => {}
+        }
+        let mut chunk = [0u8; 4096];
+        let n = client.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "closed before sending a hostname"));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+// Bounds-checked reader over a byte slice.
+struct Cursor<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.data.len() < n {
+            return None;
+        }
+        let (head, rest) = self.data.split_at(n);
+        self.data = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<usize> {
+        self.take(1).map(|b| b[0] as usize)
+    }
+
+    fn u16(&mut self) -> Option<usize> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|b| ((b[0] as usize) << 16) | ((b[1] as usize) << 8) | b[2] as usize)
+    }
+
+    // Length-prefixed field as a sub-cursor.
+    fn vec8(&mut self) -> Option<Cursor<'a>> {
+        let n = self.u8()?;
+        self.take(n).map(|data| Cursor { data })
+    }
+
+    fn vec16(&mut self) -> Option<Cursor<'a>> {
+        let n = self.u16()?;
+        self.take(n).map(|data| Cursor { data })
+    }
+}
+
+// server_name from a TLS ClientHello (RFC 8446 4.1.2 / RFC 6066 3). Only the
+// first record is inspected; ClientHellos are not fragmented in practice.
+fn parse_client_hello(buf: &[u8]) -> Peek {
+    let mut record = Cursor { data: buf };
+    let header = (record.u8(), record.u16(), record.u16());
+    let (Some(0x16), Some(_version), Some(len)) = header else {
+        return Peek::Incomplete;
+    };
+    let Some(body) = record.take(len) else {
+        return Peek::Incomplete;
+    };
+
+    let hello = (|| {
+        let mut hs = Cursor { data: body };
+        if hs.u8()? != 0x01 {
+            return Some(Err("TLS handshake is not a ClientHello"));
+        }
+        let len = hs.u24()?;
+        let mut ch = Cursor { data: hs.take(len)? };
+        ch.take(2 + 32)?; // legacy_version, random
+        ch.vec8()?; // legacy_session_id
+        ch.vec16()?; // cipher_suites
+        ch.vec8()?; // legacy_compression_methods
+        let mut exts = ch.vec16()?;
+        while let Some(ext_type) = exts.u16() {
+            let mut ext = exts.vec16()?;
+            if ext_type != 0 {
+                continue;
+            }
+            let mut names = ext.vec16()?;
+            while let Some(name_type) = names.u8() {
+                let name = names.vec16()?;
+                if name_type == 0 {
+                    return Some(std::str::from_utf8(name.data).map(str::to_string).map_err(|_| "invalid SNI"));
+                }
+            }
+        }
+        Some(Err("TLS ClientHello without SNI"))
+    })();
+    match hello {
+        Some(Ok(name)) => Peek::Found(name),
+        Some(Err(why)) => Peek::Invalid(why),
+        // The record was complete, so a short read inside it means garbage.
+        None => Peek::Invalid("malformed TLS ClientHello"),
+    }
+}
+
+// Host header of a plaintext HTTP/1.x request, without the port.
+fn parse_http_host(buf: &[u8]) -> Peek {
+    let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") else {
+        return Peek::Incomplete;
+    };
+    let Ok(head) = std::str::from_utf8(&buf[..end]) else {
+        return Peek::Invalid("request head is not valid UTF-8");
+    };
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+    if !request_line.ends_with("HTTP/1.1") && !request_line.ends_with("HTTP/1.0") {
+        return Peek::Invalid("neither a TLS ClientHello nor an HTTP/1.x request");
+    }
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        if name.trim().eq_ignore_ascii_case("host") {
+            let value = value.trim();
+            // Strip the port, keeping [ipv6] literals intact.
+            let host = match value.rsplit_once(':') {
+                Some((host, port)) if !host.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) && !value.ends_with(']') => host,
+                _ => value,
+            };
+            return Peek::Found(host.trim_start_matches('[').trim_end_matches(']').to_string());
+        }
+    }
+    Peek::Invalid("HTTP request without Host header")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vec16(data: &[u8]) -> Vec<u8> {
+        let mut v = (data.len() as u16).to_be_bytes().to_vec();
+        v.extend_from_slice(data);
+        v
+    }
+
+    // A TLS 1.3-style ClientHello record, with server_name if `sni` is set.
+    fn client_hello(sni: Option<&str>) -> Vec<u8> {
+        let mut exts = Vec::new();
+        // supported_versions, before server_name as browsers send it.
+        exts.extend_from_slice(&[0x00, 0x2b]);
+        exts.extend(vec16(&[0x02, 0x03, 0x04]));
+        if let Some(name) = sni {
+            let mut entry = vec![0x00];
+            entry.extend(vec16(name.as_bytes()));
+            exts.extend_from_slice(&[0x00, 0x00]);
+            exts.extend(vec16(&vec16(&entry)));
+        }
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[0x42; 32]);
+        hello.push(0); // legacy_session_id
+        hello.extend(vec16(&[0x13, 0x01]));
+        hello.extend_from_slice(&[0x01, 0x00]); // legacy_compression_methods
+        hello.extend(vec16(&exts));
+
+        let mut handshake = vec![0x01];
+        handshake.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+        handshake.extend(hello);
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend(vec16(&handshake));
+        record
+    }
+
+    #[test]
+    fn client_hello_sni() {
+        let record = client_hello(Some("app.internal"));
+        assert_eq!(parse_client_hello(&record), Peek::Found("app.internal".to_string()));
+        // Application data after the record is left alone.
+        let mut more = record.clone();
+        more.extend_from_slice(&[0x17, 0x03, 0x03]);
+        assert_eq!(parse_client_hello(&more), Peek::Found("app.internal".to_string()));
+    }
+
+    #[test]
+    fn truncated_client_hello_is_incomplete() {
+        let record = client_hello(Some("app.internal"));
+        for len in 0..record.len() {
+            assert_eq!(parse_client_hello(&record[..len]), Peek::Incomplete, "{len} bytes");
+        }
+    }
+
+    #[test]
+    fn bad_client_hellos() {
+        assert_eq!(
+            parse_client_hello(&client_hello(None)),
+            Peek::Invalid("TLS ClientHello without SNI")
+        );
+
+        let mut server_hello = client_hello(Some("app.internal"));
+        server_hello[5] = 0x02;
+        assert_eq!(
+            parse_client_hello(&server_hello),
+            Peek::Invalid("TLS handshake is not a ClientHello")
+        );
+
+        // Complete record whose handshake claims more than the record holds.
+        let mut short = client_hello(Some("app.internal"));
+        short[7] = 0xff;
+        assert_eq!(parse_client_hello(&short), Peek::Invalid("malformed TLS ClientHello"));
+    }
+
+    #[test]
+    fn http_host() {
+        let found = |head: &str| parse_http_host(head.as_bytes());
+        assert_eq!(
+            found("GET / HTTP/1.1\r\nHost: app.internal\r\n\r\n"),
+            Peek::Found("app.internal".to_string())
+        );
+        // Header names are case-insensitive; the port is dropped.
+        assert_eq!(
+            found("GET / HTTP/1.1\r\nUser-Agent: x\r\nhOsT:App.Internal:8080 \r\n\r\n"),
+            Peek::Found("App.Internal".to_string())
+        );
+        assert_eq!(found("GET / HTTP/1.0\r\nHOST: [::1]:80\r\n\r\n"), Peek::Found("::1".to_string()));
+        assert_eq!(found("GET / HTTP/1.1\r\nHost: [::1]\r\n\r\n"), Peek::Found("::1".to_string()));
+    }
+
+    #[test]
+    fn bad_http_requests() {
+        let peek = |head: &str| parse_http_host(head.as_bytes());
+        assert_eq!(peek("GET / HTTP/1.1\r\nHost: app.internal\r\n"), Peek::Incomplete);
+        assert_eq!(
+            peek("GET / HTTP/1.1\r\nAccept: */*\r\n\r\n"),
+            Peek::Invalid("HTTP request without Host header")
+        );
+        assert_eq!(
+            peek("SSH-2.0-OpenSSH_9.6\r\n\r\n"),
+            Peek::Invalid("neither a TLS ClientHello nor an HTTP/1.x request")
+        );
+        assert_eq!(
+            parse_http_host(b"GET / HTTP/1.1\r\nHost: \xff\r\n\r\n"),
+            Peek::Invalid("request head is not valid UTF-8")
+        );
+    }
+
+    #[test]
+    fn loopback_binds() {
+        for bind in ["127.0.0.1", "127.0.0.2", "localhost", "::1", "[::1]"] {
+            assert!(is_loopback(bind), "{bind}");
+        }
+        for bind in ["0.0.0.0", "::", "192.168.1.10"] {
+            assert!(!is_loopback(bind), "{bind}");
+        }
+    }
+}
@@ -0,0 +1,31 @@
+#[cfg(feature = "native-ssh")]
+mod native;
+mod output;
+mod process;
+mod prompt;
+#[cfg(unix)]
+mod pty;
+#[cfg(not(unix))]
+mod pty_blocking;
+
+#[cfg(feature = "native-ssh")]
+pub(crate) use native::{check_native_args, run_native};
+pub(crate) use process::run_ssh_process;
+#[cfg(unix)]
+pub(crate) use pty::run_ssh_with_pty;
+#[cfg(not(unix))]
+pub(crate) use pty_blocking::run_ssh_with_pty;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SshExit {
+    pub(crate) code: i32,
+    pub(crate) auth_failed: bool,
+}
+
+const HOST_KEY_HINT: &str = "Please add an ssh option like: -o StrictHostKeyChecking=accept-new (recommended) \
+or pre-populate known_hosts, then retry.";
+
+// Authentication failure (wrong password / key rejected / password auth disabled / etc.)
+fn is_auth_failure(lower: &str) -> bool {
+    lower.contains("permission denied") || lower.contains("too many authentication failures")
+}
@@ -0,0 +1,403 @@
+use std::io;
+use std::sync::Arc;
+
+use russh::client::{self, AuthResult, Handle, Session};
+use russh::keys::agent::AgentIdentity;
+use russh::keys::{self, PrivateKeyWithHashAlg, PublicKeyOrCertificate};
+use russh::{Channel, Disconnect};
+use tokio::io::copy_bidirectional;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+use tokio::time::{timeout, Duration};
+use tracing::{error, warn, Instrument};
+
+use super::{SshExit, HOST_KEY_HINT};
+use crate::config::{ForwardKind, ForwardingRule};
+use crate::ssh_args::{expand_tilde_path, parse_host_port};
+use crate::status::RecentOutput;
+
+// Same values the ssh runners pass via -o (ConnectTimeout / ServerAlive*).
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+const KEEPALIVE_MAX: usize = 3;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum HostKeyPolicy {
+    // Only keys already in ~/.ssh/known_hosts (OpenSSH default, minus the prompt)
+    Strict,
+    // StrictHostKeyChecking=accept-new: record unknown keys, reject changed ones
+    AcceptNew,
+    // StrictHostKeyChecking=no: accept anything
+    Off,
+}
+
+// The native backend has no ssh_config and takes none of ssh's other flags;
+// only `-o StrictHostKeyChecking=...`, the option the docs recommend, is
+// understood. Anything else (-J, ProxyCommand, -i, ...) would silently change
+// nothing, so it is refused.
+fn host_key_policy(rule: &ForwardingRule) -> Result<HostKeyPolicy, String> {
+    let unsupported = |arg: &str| {
+        format!(
+            "ssh_extra_args \"{}\" is not supported by backend = \"native\" (only -o StrictHostKeyChecking=...)",
+            arg
+        )
+    };
+    let mut policy = HostKeyPolicy::Strict;
+    let mut args = rule.ssh_extra_args.iter();
+    while let Some(arg) = args.next() {
+        let option = match arg.strip_prefix("-o") {
+            Some("") => args.next().ok_or_else(|| unsupported(arg))?.as_str(),
+            Some(option) => option,
+            None => return Err(unsupported(arg)),
+        };
+        // "Key=value" or "Key value", as in ssh_config.
+        let (key, value) = option
+            .split_once(['=', ' '])
+            .ok_or_else(|| unsupported(option))?;
+        if !key.trim().eq_ignore_ascii_case("StrictHostKeyChecking") {
+            return Err(unsupported(option));
+        }
+        policy = match value.trim_start_matches([' ', '=']).trim().to_ascii_lowercase().as_str() {
+            "accept-new" => HostKeyPolicy::AcceptNew,
+            "no" | "off" => HostKeyPolicy::Off,
+            "yes" | "ask" => HostKeyPolicy::Strict,
+            _ => return Err(unsupported(option)),
+        };
+    }
+    Ok(policy)
+}
+
+// Check a rule's ssh_extra_args before it is started (see `host_key_policy`).
+pub(crate) fn check_native_args(rule: &ForwardingRule) -> Result<(), String> {
+    host_key_policy(rule).map(|_| ())
+}
+
+// Session messages go to the log and, like the ssh runners' output, to the
+// rule's recent output.
+#[derive(Clone)]
+struct Output(Option<Arc<RecentOutput>>);
+
+impl Output {
+    fn warn(&self, line: String) {
+        warn!(target: "ssh", "{}", line);
+        self.record(&line);
+    }
+
+    fn error(&self, line: String) {
+        error!(target: "ssh", "{}", line);
+        self.record(&line);
+    }
+
+    fn record(&self, line: &str) {
+        if let Some(recent) = &self.0 {
+            recent.push(line);
+        }
+    }
+}
+
+struct Client {
+    host: String,
+    port: u16,
+    policy: HostKeyPolicy,
+    // Where connections arriving over a remote (-R) forward are delivered.
+    local_target: Option<(String, u16)>,
+    // Fires (or is dropped) when the session ends, with the reason if known.
+    closed_tx: Option<oneshot::Sender<String>>,
+    output: Output,
+}
+
+impl client::Handler for Client {
+    type Error = russh::Error;
+
+    async fn check_server_key(
+        &mut self,
+        server_public_key: &PublicKeyOrCertificate,
+    ) -> Result<bool, Self::Error> {
+        let PublicKeyOrCertificate::PublicKey { key, .. } = server_public_key else {
+            self.output.error("Host certificates are not supported by the native backend.".to_string());
+            return Ok(false);
+        };
+        if self.policy == HostKeyPolicy::Off {
+            return Ok(true);
+        }
+        match keys::check_known_hosts(&self.host, self.port, key) {
+            Ok(true) => Ok(true),
+            Ok(false) if self.policy == HostKeyPolicy::AcceptNew => {
+                if let Err(e) = keys::known_hosts::learn_known_hosts(&self.host, self.port, key) {
+                    self.output.warn(format!("Failed to record host key for {}: {}", self.host, e));
+                }
+                Ok(true)
+            }
+            Ok(false) => {
+                self.output.error(format!("Host key for {} is not known. {}", self.host, HOST_KEY_HINT));
+                Ok(false)
+            }
+            Err(e) => {
+                self.output.error(format!("Host key verification failed for {}: {}", self.host, e));
+                Ok(false)
+            }
+        }
+    }
+
+    async fn server_channel_open_forwarded_tcpip(
+        &mut self,
+        channel: Channel<client::Msg>,
+        _connected_address: &str,
+        _connected_port: u32,
+        _originator_address: &str,
+        _originator_port: u32,
+        reply: client::ChannelOpenHandle,
+        _session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let Some((host, port)) = self.local_target.clone() else {
+            // Dropping the reply rejects the channel.
+            return Ok(());
+        };
+        reply.accept().await;
+        let output = self.output.clone();
+        tokio::spawn(async move {
+            match TcpStream::connect((host.as_str(), port)).await {
+                Ok(mut tcp) => {
+                    let mut stream = channel.into_stream();
+                    let _ = copy_bidirectional(&mut tcp, &mut stream).await;
+                }
+                Err(e) => {
+                    output.warn(format!("connect to {}:{} failed: {}", host, port, e));
+                }
+            }
+        });
+        Ok(())
+    }
+
+    async fn disconnected(
+        &mut self,
+        reason: client::DisconnectReason<Self::Error>,
+    ) -> Result<(), Self::Error> {
+        let (text, res) = match reason {
+            client::DisconnectReason::ReceivedDisconnect(info) => {
+                (format!("disconnected by server: {}", info.message), Ok(()))
+            }
+            client::DisconnectReason::Error(e) => (format!("connection lost: {}", e), Err(e)),
+        };
+        if let Some(tx) = self.closed_tx.take() {
+            let _ = tx.send(text);
+        }
+        res
+    }
+}
+
+// In-process SSH client: same inputs and exit semantics as the ssh runners
+// (code 0 on requested shutdown, auth_failed on rejected credentials), so the
+// supervisor's restart logic applies unchanged. `ready_tx` fires once the
+// forward is set up.
+pub(crate) async fn run_native(
+    rule: &ForwardingRule,
+    recent: Option<Arc<RecentOutput>>,
+    ready_tx: oneshot::Sender<()>,
+    mut kill_rx: oneshot::Receiver<()>,
+) -> io::Result<SshExit> {
+    // Rejected by `transport::for_rule`; no SOCKS server in the native client.
+    if rule.kind == ForwardKind::Dynamic {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "dynamic forwards need backend = \"ssh\""));
+    }
+    let policy = host_key_policy(rule).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let output = Output(recent);
+    let (dst_host, dst_port) =
+        parse_host_port(&rule.remote_address).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let failed = SshExit {
+        code: 1,
+        auth_failed: false,
+    };
+
+    let config = Arc::new(client::Config {
+        keepalive_interval: Some(KEEPALIVE_INTERVAL),
+        keepalive_max: KEEPALIVE_MAX,
+        inactivity_timeout: None,
+        ..Default::default()
+    });
+    let (closed_tx, closed_rx) = oneshot::channel();
+    let handler = Client {
+        host: rule.ssh_host.clone(),
+        port: rule.ssh_port,
+        policy,
+        local_target: (rule.kind == ForwardKind::Remote).then(|| (rule.local_bind.clone(), rule.local_port)),
+        closed_tx: Some(closed_tx),
+        output: output.clone(),
+    };
+
+    let connect = client::connect(config, (rule.ssh_host.as_str(), rule.ssh_port), handler);
+    let mut session = tokio::select! {
+        res = timeout(CONNECT_TIMEOUT, connect) => match res {
+            Ok(Ok(session)) => session,
+            Ok(Err(e)) => {
+                output.warn(format!("ssh: connect to host {} port {}: {}", rule.ssh_host, rule.ssh_port, e));
+                return Ok(failed);
+            }
+            Err(_) => {
+                output.warn(format!("ssh: connect to host {} port {}: Connection timed out", rule.ssh_host, rule.ssh_port));
+                return Ok(failed);
+            }
+        },
+        _ = &mut kill_rx => return Ok(SshExit { code: 0, auth_failed: false }),
+    };
+
+    match authenticate(&mut session, rule, &output).await {
+        Ok(true) => {}
+        Ok(false) => {
+            output.error(format!("{}@{}: Permission denied (native backend).", rule.ssh_user, rule.ssh_host));
+            return Ok(SshExit {
+                code: 1,
+                auth_failed: true,
+            });
+        }
+        Err(e) => {
+            output.warn(format!("ssh: authentication error: {}", e));
+            return Ok(failed);
+        }
+    }
+
+    let session = Arc::new(session);
+    // Set up the forward; failing here is the ExitOnForwardFailure case.
+    let listener = if rule.kind == ForwardKind::Remote {
+        if let Err(e) = session.tcpip_forward(dst_host.as_str(), dst_port as u32).await {
+            output.warn(format!(
+                "Error: remote port forwarding failed for listen port {}: {}",
+                dst_port, e
+            ));
+            disconnect(&session).await;
+            return Ok(failed);
+        }
+        None
+    } else {
+        match TcpListener::bind((rule.local_bind.as_str(), rule.local_port)).await {
+            Ok(l) => Some(l),
+            Err(e) => {
+                output.warn(format!(
+                    "bind [{}]:{}: {}; could not request local forwarding",
+                    rule.local_bind, rule.local_port, e
+                ));
+                disconnect(&session).await;
+                return Ok(failed);
+            }
+        }
+    };
+
+    let _ = ready_tx.send(());
+
+    let accept = async {
+        let Some(listener) = listener else {
+            return std::future::pending::<io::Error>().await;
+        };
+        loop {
+            let (mut tcp, peer) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => return e,
+            };
+            let session = session.clone();
+            let (host, port) = (dst_host.clone(), dst_port);
+            let output = output.clone();
+            tokio::spawn(async move {
+                let channel = session
+                    .channel_open_direct_tcpip(host.as_str(), port as u32, peer.ip().to_string(), peer.port() as u32)
+                    .await;
+                match channel {
+                    Ok(channel) => {
+                        let mut stream = channel.into_stream();
+                        let _ = copy_bidirectional(&mut tcp, &mut stream).await;
+                    }
+                    Err(e) => {
+                        output.warn(format!("channel {}:{}: open failed: {}", host, port, e));
+                    }
+                }
+            }
+            .in_current_span());
+        }
+    };
+
+    tokio::select! {
+        // Shutdown requested by supervisor: close the session cleanly.
+        _ = &mut kill_rx => {
+            disconnect(&session).await;
+            Ok(SshExit { code: 0, auth_failed: false })
+        }
+        reason = closed_rx => {
+            output.warn(format!(
+                "ssh: {}",
+                reason.unwrap_or_else(|_| "connection closed".to_string())
+            ));
+            Ok(failed)
+        }
+        e = accept => {
+            output.warn(format!("accept on {}:{} failed: {}", rule.local_bind, rule.local_port, e));
+            disconnect(&session).await;
+            Ok(failed)
+        }
+    }
+}
+
+// Try the same methods as OpenSSH, in the same order: public key (configured
+// key file, else ssh-agent identities), then password. Ok(false) = rejected.
+async fn authenticate(session: &mut Handle<Client>, rule: &ForwardingRule, output: &Output) -> Result<bool, russh::Error> {
+    let user = rule.ssh_user.as_str();
+    let rsa_hash = session.best_supported_rsa_hash().await?.flatten();
+
+    if let Some(key_path) = &rule.ssh_key_path {
+        // ssh_password doubles as the key passphrase, like in PTY mode.
+        match keys::load_secret_key(expand_tilde_path(key_path), rule.password()) {
+            Ok(key) => {
+                let key = PrivateKeyWithHashAlg::new(Arc::new(key), rsa_hash);
+                if session.authenticate_publickey(user, key).await?.success() {
+                    return Ok(true);
+                }
+            }
+            Err(e) => output.warn(format!("Load key {} failed: {}", key_path, e)),
+        }
+    } else if let Some(true) = try_agent(session, user, rsa_hash).await {
+        return Ok(true);
+    }
+
+    if let Some(pw) = rule.password() {
+        let res = session.authenticate_password(user, pw).await?;
+        return Ok(matches!(res, AuthResult::Success));
+    }
+    Ok(false)
+}
+
+#[cfg(unix)]
+async fn try_agent(
+    session: &mut Handle<Client>,
+    user: &str,
+    rsa_hash: Option<keys::HashAlg>,
+) -> Option<bool> {
+    let mut agent = keys::agent::client::AgentClient::connect_env().await.ok()?;
+    let identities = agent.request_identities().await.ok()?;
+    for identity in identities {
+        let AgentIdentity::PublicKey { key, .. } = identity else {
+            continue;
+        };
+        if let Ok(res) = session
+            .authenticate_publickey_with(user, key, rsa_hash, &mut agent)
+            .await
+        {
+            if res.success() {
+                return Some(true);
+            }
+        }
+    }
+    Some(false)
+}
+
+#[cfg(not(unix))]
+async fn try_agent(
+    _session: &mut Handle<Client>,
+    _user: &str,
+    _rsa_hash: Option<keys::HashAlg>,
+) -> Option<bool> {
+    None
+}
+
+async fn disconnect(session: &Handle<Client>) {
+    let _ = session
+        .disconnect(Disconnect::ByApplication, "", "en")
+        .await;
+}
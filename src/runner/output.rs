@@ -0,0 +1,210 @@
+use std::sync::Arc;
+
+use tracing::{error, info, warn, Level};
+
+use crate::status::RecentOutput;
+
+// Longer partial lines (e.g. a banner without newlines) are logged in pieces.
+const MAX_LINE: usize = 4096;
+
+// Turns ssh output, which arrives in arbitrary chunks, into log lines: split
+// on CR / LF, terminal escape sequences removed, blank lines dropped. Lines
+// are logged under the runner's `tunnel` span, so they carry the rule name,
+// and kept in the rule's recent output.
+pub(super) struct OutputLines<'a> {
+    partial: Vec<u8>,
+    // Masked if it shows up, e.g. echoed back by a terminal that did not turn echo off.
+    secret: Option<&'a str>,
+    recent: Option<Arc<RecentOutput>>,
+}
+
+impl<'a> OutputLines<'a> {
+    pub(super) fn new(secret: Option<&'a str>, recent: Option<Arc<RecentOutput>>) -> Self {
+        Self {
+            partial: Vec::new(),
+            secret: secret.filter(|s| !s.is_empty()),
+            recent,
+        }
+    }
+
+    pub(super) fn feed(&mut self, chunk: &[u8]) {
+        for &b in chunk {
+            match b {
+                b'\n' | b'\r' => self.flush(),
+                _ => self.partial.push(b),
+            }
+        }
+        if self.partial.len() > MAX_LINE {
+            self.flush();
+        }
+    }
+
+    // Log what is left of an unterminated last line (e.g. a prompt).
+    pub(super) fn flush(&mut self) {
+        if self.partial.is_empty() {
+            return;
+        }
+        let line = String::from_utf8_lossy(&self.partial).into_owned();
+        self.partial.clear();
+        self.line(&line);
+    }
+
+    // Log one complete line (without its line break).
+    pub(super) fn line(&mut self, line: &str) {
+        let mut line = clean(line);
+        if let Some(secret) = self.secret {
+            line = line.replace(secret, "****");
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            return;
+        }
+        if let Some(recent) = &self.recent {
+            recent.push(line);
+        }
+        log_line(line);
+    }
+}
+
+// Log one line of ssh output at a level matching its content.
+fn log_line(line: &str) {
+    match level(line) {
+        Level::ERROR => error!(target: "ssh", "{}", line),
+        Level::WARN => warn!(target: "ssh", "{}", line),
+        _ => info!(target: "ssh", "{}", line),
+    }
+}
+
+// Errors for failed connections / forwards / authentication, warnings for
+// ssh's own "Warning:" lines, info for everything else (banners, MOTD, prompts).
+fn level(line: &str) -> Level {
+    const ERRORS: [&str; 12] = [
+        "permission denied",
+        "too many authentication failures",
+        "host key verification failed",
+        "connection refused",
+        "connection timed out",
+        "connection closed",
+        "no route to host",
+        "could not resolve",
+        "could not request",
+        "forwarding failed",
+        "broken pipe",
+        "error",
+    ];
+    let lower = line.to_lowercase();
+    if lower.starts_with("warning:") || lower.starts_with('@') {
+        Level::WARN
+    } else if ERRORS.iter().any(|e| lower.contains(e)) {
+        Level::ERROR
+    } else {
+        Level::INFO
+    }
+}
+
+// Drop ANSI escape sequences (CSI, OSC, two-byte escapes) and other control characters.
+fn clean(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => match chars.next() {
+                // CSI: parameters up to a final byte in '@'..='~'.
+                Some('[') => {
+                    for c in chars.by_ref() {
+                        if ('@'..='~').contains(&c) {
+                            break;
+                        }
+                    }
+                }
+                // OSC: up to BEL or ST (ESC \).
+                Some(']') => {
+                    while let Some(c) = chars.next() {
+                        if c == '\x07' || (c == '\x1b' && chars.next() == Some('\\')) {
+                            break;
+                        }
+                    }
+                }
+                _ => {}
+            },
+            '\t' => out.push(' '),
+            c if c.is_control() => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // What the rule's recent output holds after `chunks`, without the timestamps.
+    fn recorded(secret: Option<&str>, chunks: &[&[u8]]) -> Vec<String> {
+        let recent = Arc::new(RecentOutput::default());
+        let mut output = OutputLines::new(secret, Some(recent.clone()));
+        for chunk in chunks {
+            output.feed(chunk);
+        }
+        output.flush();
+        lines(&recent)
+    }
+
+    fn lines(recent: &RecentOutput) -> Vec<String> {
+        recent
+            .lines()
+            .iter()
+            .map(|l| l.split_once(' ').expect("timestamp prefix").1.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn echoed_password_is_masked() {
+        assert_eq!(
+            recorded(Some("hunter2"), &[b"tester@bastion's password: hunter2\r\n"]),
+            ["tester@bastion's password: ****"]
+        );
+        // Also when the echo arrives in pieces.
+        assert_eq!(recorded(Some("hunter2"), &[b"hun", b"ter2\r\n", b"Last login"]), ["****", "Last login"]);
+        // An empty password masks nothing.
+        assert_eq!(recorded(Some(""), &[b"no password\n"]), ["no password"]);
+    }
+
+    #[test]
+    fn carriage_returns_split_progress_lines() {
+        assert_eq!(recorded(None, &[b"10%\r20%\r", b"30%\r\n\r\ndone"]), ["10%", "20%", "30%", "done"]);
+    }
+
+    #[test]
+    fn escape_sequences_split_across_reads() {
+        assert_eq!(
+            recorded(None, &[b"\x1b[3", b"1mred\x1b", b"[0m plain\x1b]0;ti", b"tle\x07\n"]),
+            ["red plain"]
+        );
+        // So are multi-byte characters.
+        assert_eq!(recorded(None, &[b"caf\xc3", b"\xa9\n"]), ["caf\u{e9}"]);
+    }
+
+    #[test]
+    fn recent_output_keeps_the_last_50_lines() {
+        let recent = Arc::new(RecentOutput::default());
+        let mut output = OutputLines::new(None, Some(recent.clone()));
+        for n in 1..=50 {
+            output.feed(format!("line {}\n", n).as_bytes());
+        }
+        assert_eq!(lines(&recent).len(), 50);
+        assert_eq!(lines(&recent)[0], "line 1");
+        output.feed(b"line 51\n");
+        let kept = lines(&recent);
+        assert_eq!(kept.len(), 50);
+        assert_eq!((kept[0].as_str(), kept[49].as_str()), ("line 2", "line 51"));
+    }
+
+    #[test]
+    fn levels() {
+        assert_eq!(level("Permission denied (publickey)."), Level::ERROR);
+        assert_eq!(level("Warning: Permanently added 'bastion' to the list of known hosts."), Level::WARN);
+        assert_eq!(level("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@"), Level::WARN);
+        assert_eq!(level("Welcome to bastion"), Level::INFO);
+    }
+}
@@ -0,0 +1,88 @@
+use std::io;
+use std::process::Stdio;
+use std::sync::Arc;
+
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+use tokio::sync::oneshot;
+use tokio::time::{timeout, Duration};
+use tracing::error;
+
+use super::output::OutputLines;
+use super::{is_auth_failure, SshExit, HOST_KEY_HINT};
+use crate::ssh_args::Invocation;
+use crate::status::RecentOutput;
+
+// Lightweight runner for rules without interactive answers (key / ssh-agent auth).
+// ssh runs with BatchMode=yes (set by build_invocation), so it never prompts and
+// needs no terminal: a plain child process with piped stderr is enough, and
+// everything stays on the async runtime (no PTY, no blocking task, no reader thread).
+pub(crate) async fn run_ssh_process(
+    inv: &Invocation,
+    recent: Option<Arc<RecentOutput>>,
+    mut kill_rx: oneshot::Receiver<()>,
+) -> io::Result<SshExit> {
+    let mut child = Command::new(&inv.program)
+        .args(&inv.args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| io::Error::other(format!("spawn ssh failed: {e}")))?;
+
+    let stderr = child
+        .stderr
+        .take()
+        .ok_or_else(|| io::Error::other("ssh stderr not captured"))?;
+    let mut lines = BufReader::new(stderr).lines();
+    let mut auth_failed = false;
+    let mut output = OutputLines::new(None, recent);
+
+    let mut inspect = |line: &str| {
+        // Forward output to the log (only stderr carries diagnostics for `ssh -N`)
+        output.line(line);
+        let lower = line.to_lowercase();
+        // BatchMode turns the host key confirmation prompt into a hard failure.
+        if lower.contains("host key verification failed") {
+            error!("Unknown or changed host key. {}", HOST_KEY_HINT);
+        }
+        if is_auth_failure(&lower) {
+            auth_failed = true;
+        }
+    };
+
+    let mut stderr_open = true;
+    let status = loop {
+        tokio::select! {
+            line = lines.next_line(), if stderr_open => match line {
+                Ok(Some(line)) => inspect(&line),
+                Ok(None) | Err(_) => stderr_open = false,
+            },
+            status = child.wait() => break status,
+            // Shutdown requested by supervisor: kill and reap the child.
+            _ = &mut kill_rx => {
+                let _ = child.kill().await;
+                return Ok(SshExit {
+                    code: 0,
+                    auth_failed: false,
+                });
+            }
+        }
+    };
+
+    // Drain output written right before exit; bounded in case a ProxyCommand
+    // grandchild still holds stderr open.
+    if stderr_open {
+        let _ = timeout(Duration::from_secs(1), async {
+            while let Ok(Some(line)) = lines.next_line().await {
+                inspect(&line);
+            }
+        })
+        .await;
+    }
+
+    let status = status.map_err(|e| io::Error::other(format!("wait failed: {e}")))?;
+    let code = if status.success() { 0 } else { 1 };
+    Ok(SshExit { code, auth_failed })
+}
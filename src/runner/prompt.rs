@@ -0,0 +1,117 @@
+use tracing::error;
+
+use super::{is_auth_failure, SshExit, HOST_KEY_HINT};
+
+// What the PTY loop should do after a chunk of ssh output was inspected.
+pub(super) enum PromptAction {
+    Continue,
+    // Write these bytes to the PTY (answer to a password / passphrase prompt).
+    Reply(Vec<u8>),
+    // Kill ssh and report this exit.
+    Abort(SshExit),
+}
+
+// Prompt detection shared by the PTY runners: answers password / passphrase
+// prompts once and flags authentication failures.
+pub(super) struct PromptResponder<'a> {
+    password: Option<&'a str>,
+    sent_password: bool,
+    // Keep a small tail to catch prompts split across chunks,
+    // but avoid matching old prompts repeatedly.
+    tail: String,
+    auth_failed: bool,
+}
+
+impl<'a> PromptResponder<'a> {
+    pub(super) fn new(password: Option<&'a str>) -> Self {
+        Self {
+            password,
+            sent_password: false,
+            tail: String::new(),
+            auth_failed: false,
+        }
+    }
+
+    pub(super) fn auth_failed(&self) -> bool {
+        self.auth_failed
+    }
+
+    pub(super) fn on_output(&mut self, chunk: &[u8]) -> PromptAction {
+        let s = String::from_utf8_lossy(chunk);
+        let combined = format!("{}{}", self.tail, s);
+        let lower = combined.to_lowercase();
+
+        // Safer default: do NOT auto-accept unknown host keys.
+        // If this prompt appears, instruct user to configure StrictHostKeyChecking in ssh_extra_args.
+        if lower.contains("are you sure you want to continue connecting") {
+            error!("Encountered host key confirmation prompt. {}", HOST_KEY_HINT);
+            return PromptAction::Abort(SshExit {
+                code: 1,
+                auth_failed: false,
+            });
+        }
+
+        // Once an authentication failure is detected, stop auto-retry for this rule.
+        if is_auth_failure(&lower) {
+            self.auth_failed = true;
+        }
+
+        let mut reply = None;
+
+        // Password prompt: answer only once to avoid infinite loops.
+        if lower.contains("password:") || lower.contains("password for") {
+            if self.sent_password {
+                error!("Password was requested again; aborting. (Check ssh_password)");
+                return PromptAction::Abort(SshExit {
+                    code: 1,
+                    auth_failed: self.auth_failed,
+                });
+            }
+            reply = self.answer();
+        }
+
+        // Key passphrase prompt (reuse ssh_password if provided).
+        if lower.contains("enter passphrase") && !self.sent_password {
+            reply = self.answer();
+        }
+
+        match reply {
+            Some(bytes) => {
+                // We just responded to a prompt: drop the tail completely so we don't
+                // re-match the same prompt on the next output chunk.
+                self.tail.clear();
+                PromptAction::Reply(bytes)
+            }
+            None => {
+                // Update tail to last N chars of combined
+                const TAIL_MAX: usize = 128;
+                self.tail = if combined.len() <= TAIL_MAX {
+                    combined
+                } else {
+                    let mut start = combined.len() - TAIL_MAX;
+                    while !combined.is_char_boundary(start) {
+                        start += 1;
+                    }
+                    combined[start..].to_string()
+                };
+                PromptAction::Continue
+            }
+        }
+    }
+
+    fn answer(&mut self) -> Option<Vec<u8>> {
+        match self.password {
+            Some(pw) => {
+                self.sent_password = true;
+                let mut bytes = pw.as_bytes().to_vec();
+                bytes.push(b'\n');
+                Some(bytes)
+            }
+            None => {
+                // No password provided but a password / passphrase prompt appeared
+                self.auth_failed = true;
+                None
+            }
+        }
+    }
+}
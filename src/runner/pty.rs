@@ -0,0 +1,240 @@
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::process::Stdio;
+use std::sync::Arc;
+
+use tokio::io::unix::AsyncFd;
+use tokio::process::Command;
+use tokio::sync::oneshot;
+
+use super::output::OutputLines;
+use super::prompt::{PromptAction, PromptResponder};
+use super::SshExit;
+use crate::ssh_args::Invocation;
+use crate::status::RecentOutput;
+
+// PTY relationship:
+// - Slave: SSH process sees this as a "terminal" interface
+//   * SSH needs a terminal to display interactive prompts (e.g., "Password:")
+//   * SSH reads user input from the terminal
+//   * Without a terminal, SSH may not show interactive prompts
+// - Master: Our control program uses this for I/O
+//   * Read: Receive SSH output (including prompts) from master
+//   * Write: Send input (e.g., password) to master, SSH receives it from slave
+//
+// Everything is event-driven on the tokio runtime: the non-blocking master fd is
+// registered with the reactor (AsyncFd), and child exit is awaited through tokio's
+// process reaper (SIGCHLD / pidfd), so there is no polling and no per-rule thread.
+pub(crate) async fn run_ssh_with_pty(
+    inv: &Invocation,
+    password: Option<&str>,
+    recent: Option<Arc<RecentOutput>>,
+    mut kill_rx: oneshot::Receiver<()>,
+) -> io::Result<SshExit> {
+    let (master, slave) = open_pty()?;
+
+    let mut cmd = Command::new(&inv.program);
+    cmd.args(&inv.args)
+        .stdin(Stdio::from(slave.try_clone()?))
+        .stdout(Stdio::from(slave.try_clone()?))
+        .stderr(Stdio::from(slave))
+        .kill_on_drop(true);
+    // SAFETY: only async-signal-safe calls between fork and exec.
+    unsafe {
+        cmd.pre_exec(|| {
+            // New session with the PTY slave (now fd 0) as controlling terminal,
+            // so ssh reads prompts from it instead of failing with "no tty".
+            if libc::setsid() == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::ioctl(0, libc::TIOCSCTTY as _, 0) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+
+    // Spawn SSH process attached to slave side (SSH thinks it's using a terminal).
+    let mut child = cmd
+        .spawn()
+        .map_err(|e| io::Error::other(format!("spawn ssh in pty failed: {e}")))?;
+    // Drop slave handles: SSH process now owns the slave side and will keep it open until it exits.
+    drop(cmd);
+
+    // SAFETY: `master` is an OwnedFd moved into the AsyncFd, so the descriptor
+    // stays open and unchanged until the AsyncFd is dropped.
+    let master = unsafe { AsyncFd::register(master) }
+        .map_err(|e| io::Error::other(format!("register pty master failed: {e}")))?;
+    let mut responder = PromptResponder::new(password);
+    let mut output = OutputLines::new(password, recent);
+    let mut buf = [0u8; 4096];
+    let mut master_open = true;
+
+    // Main loop: handle shutdown, forward output, respond to prompts, and wait for process exit.
+    let status = loop {
+        tokio::select! {
+            // Shutdown requested by supervisor: kill and reap the child.
+            _ = &mut kill_rx => {
+                let _ = child.kill().await;
+                output.flush();
+                return Ok(SshExit {
+                    code: 0,
+                    auth_failed: false,
+                });
+            }
+            status = child.wait() => break status,
+            res = read_master(&master, &mut buf), if master_open => {
+                let n = match res {
+                    Ok(n) if n > 0 => n,
+                    // EOF / EIO: all slave handles are closed; wait for the process.
+                    _ => {
+                        master_open = false;
+                        continue;
+                    }
+                };
+                // Forward output to the log (PTY mixes stdout/stderr)
+                output.feed(&buf[..n]);
+
+                match responder.on_output(&buf[..n]) {
+                    PromptAction::Continue => {}
+                    PromptAction::Reply(bytes) => {
+                        write_master(&master, &bytes).await.map_err(|e| {
+                            io::Error::new(
+                                io::ErrorKind::BrokenPipe,
+                                format!("write password failed: {e}"),
+                            )
+                        })?;
+                    }
+                    PromptAction::Abort(exit) => {
+                        let _ = child.kill().await;
+                        output.flush();
+                        return Ok(exit);
+                    }
+                }
+            }
+        }
+    };
+
+    let status = status.map_err(|e| io::Error::other(format!("wait failed: {e}")))?;
+    // Flush output written right before exit (still buffered in the PTY).
+    while master_open {
+        match master.get_ref().read_nonblocking(&mut buf) {
+            Ok(n) if n > 0 => {
+                output.feed(&buf[..n]);
+                if let PromptAction::Abort(exit) = responder.on_output(&buf[..n]) {
+                    output.flush();
+                    return Ok(exit);
+                }
+            }
+            _ => master_open = false,
+        }
+    }
+    output.flush();
+
+    let code = if status.success() { 0 } else { 1 };
+    Ok(SshExit {
+        code,
+        auth_failed: responder.auth_failed(),
+    })
+}
+
+// Open a PTY pair; the master is non-blocking (for AsyncFd), both ends close-on-exec
+// so concurrently spawned ssh processes don't inherit each other's terminals.
+fn open_pty() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut master = -1;
+    let mut slave = -1;
+    let mut size = libc::winsize {
+        ws_row: 24,
+        ws_col: 120,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    // SAFETY: out-pointers are valid; name and termios are optional (null).
+    let rc = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            // *const on Linux, *mut on the BSDs/macOS
+            std::ptr::addr_of_mut!(size),
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::other(format!(
+            "openpty failed: {}",
+            io::Error::last_os_error()
+        )));
+    }
+    // SAFETY: openpty succeeded, so both fds are open and owned by us.
+    let (master, slave) = unsafe { (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+    set_fd_flags(&master, true)?;
+    set_fd_flags(&slave, false)?;
+    Ok((master, slave))
+}
+
+fn set_fd_flags(fd: &OwnedFd, nonblocking: bool) -> io::Result<()> {
+    let raw = fd.as_raw_fd();
+    // SAFETY: plain fcntl calls on an fd we own.
+    unsafe {
+        if libc::fcntl(raw, libc::F_SETFD, libc::FD_CLOEXEC) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        if nonblocking {
+            let flags = libc::fcntl(raw, libc::F_GETFL);
+            if flags == -1 || libc::fcntl(raw, libc::F_SETFL, flags | libc::O_NONBLOCK) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+trait NonBlockingFd {
+    fn read_nonblocking(&self, buf: &mut [u8]) -> io::Result<usize>;
+    fn write_nonblocking(&self, buf: &[u8]) -> io::Result<usize>;
+}
+
+impl NonBlockingFd for OwnedFd {
+    fn read_nonblocking(&self, buf: &mut [u8]) -> io::Result<usize> {
+        // SAFETY: buf is valid for buf.len() bytes.
+        let n = unsafe { libc::read(self.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+        if n < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(n as usize)
+        }
+    }
+
+    fn write_nonblocking(&self, buf: &[u8]) -> io::Result<usize> {
+        // SAFETY: buf is valid for buf.len() bytes.
+        let n = unsafe { libc::write(self.as_raw_fd(), buf.as_ptr().cast(), buf.len()) };
+        if n < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(n as usize)
+        }
+    }
+}
+
+async fn read_master(master: &AsyncFd<OwnedFd>, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+        let mut guard = master.readable().await?;
+        match guard.try_io(|fd| fd.get_ref().read_nonblocking(buf)) {
+            Ok(res) => return res,
+            Err(_would_block) => continue,
+        }
+    }
+}
+
+async fn write_master(master: &AsyncFd<OwnedFd>, mut bytes: &[u8]) -> io::Result<()> {
+    while !bytes.is_empty() {
+        let mut guard = master.writable().await?;
+        match guard.try_io(|fd| fd.get_ref().write_nonblocking(bytes)) {
+            Ok(Ok(n)) => bytes = &bytes[n..],
+            Ok(Err(e)) => return Err(e),
+            Err(_would_block) => continue,
+        }
+    }
+    Ok(())
+}
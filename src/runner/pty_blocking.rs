@@ -0,0 +1,168 @@
+use std::io::{self, Read, Write};
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+use portable_pty::{CommandBuilder, PtySize};
+use tokio::sync::oneshot;
+
+use super::output::OutputLines;
+use super::prompt::{PromptAction, PromptResponder};
+use super::SshExit;
+use crate::ssh_args::Invocation;
+use crate::status::RecentOutput;
+
+// Fallback PTY runner for platforms without AsyncFd (Windows ConPTY via portable-pty).
+// portable-pty uses blocking I/O, so the runner lives on a blocking task and the
+// kill signal is bridged from the async side.
+pub(crate) async fn run_ssh_with_pty(
+    inv: &Invocation,
+    password: Option<&str>,
+    recent: Option<Arc<RecentOutput>>,
+    kill_rx: oneshot::Receiver<()>,
+) -> io::Result<SshExit> {
+    let inv = inv.clone();
+    let password = password.map(str::to_string);
+    let (kill_tx, blocking_kill_rx) = mpsc::channel::<()>();
+    // Keep logging under the rule's span on the blocking thread.
+    let span = tracing::Span::current();
+    let mut handle = tokio::task::spawn_blocking(move || {
+        span.in_scope(|| run_blocking(&inv, password.as_deref(), recent, blocking_kill_rx))
+    });
+    tokio::select! {
+        res = &mut handle => res.map_err(io::Error::other)?,
+        _ = kill_rx => {
+            let _ = kill_tx.send(());
+            handle.await.map_err(io::Error::other)?
+        }
+    }
+}
+
+fn run_blocking(
+    inv: &Invocation,
+    password: Option<&str>,
+    recent: Option<Arc<RecentOutput>>,
+    kill_rx: mpsc::Receiver<()>,
+) -> io::Result<SshExit> {
+    // Use the native pty implementation for the system
+    let pty_system = portable_pty::native_pty_system();
+    // Create a new pty
+    let pair = pty_system
+        .openpty(PtySize {
+            rows: 24,
+            cols: 120,
+            pixel_width: 0,
+            pixel_height: 0,
+        })
+        .map_err(|e| io::Error::other(format!("openpty failed: {e}")))?;
+
+    let mut cmd = CommandBuilder::new(&inv.program);
+    for a in &inv.args {
+        cmd.arg(a);
+    }
+
+    // Spawn SSH process attached to slave side (SSH thinks it's using a terminal).
+    let mut child = pair.slave.spawn_command(cmd).map_err(|e| {
+        io::Error::other(format!("spawn ssh in pty failed: {e}"))
+    })?;
+    // Drop slave handle: SSH process now owns the slave side and will keep it open until it exits.
+    drop(pair.slave);
+
+    let mut reader = pair.master.try_clone_reader().map_err(|e| {
+        io::Error::other(format!("pty reader failed: {e}"))
+    })?;
+    let mut writer = pair.master.take_writer().map_err(|e| {
+        io::Error::other(format!("pty writer failed: {e}"))
+    })?;
+
+    // Read PTY output on a dedicated thread and forward via mpsc.
+    let (out_tx, out_rx) = mpsc::channel::<Vec<u8>>();
+    let reader_handle = thread::spawn(move || {
+        let mut buf = [0u8; 4096];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    if out_tx.send(buf[..n].to_vec()).is_err() {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    });
+
+    let mut responder = PromptResponder::new(password);
+    let mut output = OutputLines::new(password, recent);
+
+    // Main loop: handle shutdown, forward output, respond to prompts, and poll process exit.
+    loop {
+        // Check shutdown (triggered by supervisor on Ctrl-C)
+        if let Ok(()) = kill_rx.try_recv() {
+            let _ = child.kill();
+            let _ = child.wait();
+            let _ = reader_handle.join();
+            output.flush();
+            return Ok(SshExit {
+                code: 0,
+                auth_failed: false,
+            });
+        }
+
+        // Use timeout to allow polling child status.
+        match out_rx.recv_timeout(std::time::Duration::from_millis(200)) {
+            Ok(chunk) => {
+                // Forward output to the log (PTY mixes stdout/stderr)
+                output.feed(&chunk);
+
+                match responder.on_output(&chunk) {
+                    PromptAction::Continue => {}
+                    PromptAction::Reply(bytes) => {
+                        writer.write_all(&bytes).map_err(|e| {
+                            io::Error::new(
+                                io::ErrorKind::BrokenPipe,
+                                format!("write password failed: {e}"),
+                            )
+                        })?;
+                        let _ = writer.flush();
+                    }
+                    PromptAction::Abort(exit) => {
+                        let _ = child.kill();
+                        let _ = child.wait();
+                        let _ = reader_handle.join();
+                        output.flush();
+                        return Ok(exit);
+                    }
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                // fall through to child polling
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                // reader ended; wait for process
+                break;
+            }
+        }
+
+        // Poll for process exit without blocking the prompt/kill handling.
+        if let Ok(Some(status)) = child.try_wait() {
+            let code = if status.success() { 0 } else { 1 };
+            let _ = reader_handle.join();
+            output.flush();
+            return Ok(SshExit {
+                code,
+                auth_failed: responder.auth_failed(),
+            });
+        }
+    }
+
+    let status = child
+        .wait()
+        .map_err(|e| io::Error::other(format!("wait failed: {e}")))?;
+    let code = if status.success() { 0 } else { 1 };
+    let _ = reader_handle.join();
+    output.flush();
+    Ok(SshExit {
+        code,
+        auth_failed: responder.auth_failed(),
+    })
+}
@@ -1,6 +1,6 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use crate::config::ForwardingRule;
+use crate::config::{ForwardKind, ForwardingRule};
 
 #[derive(Debug, Clone)]
 pub struct Invocation {
@@ -8,7 +8,7 @@ pub struct Invocation {
     pub args: Vec<String>,
 }
 
-fn parse_host_port(s: &str) -> Result<(String, u16), String> {
+pub(crate) fn parse_host_port(s: &str) -> Result<(String, u16), String> {
     // Supports "host:port" and "[ipv6]:port"
     if let Some(rest) = s.strip_prefix('[') {
         let (host, rest) = rest
@@ -35,54 +35,139 @@ fn parse_host_port(s: &str) -> Result<(String, u16), String> {
     Ok((host.to_string(), port))
 }
 
-fn expand_tilde_path(p: &str) -> PathBuf {
+pub(crate) fn expand_tilde_path(p: &str) -> PathBuf {
     PathBuf::from(shellexpand::tilde(p).to_string())
 }
 
 pub fn build_invocation(rule: &ForwardingRule) -> Result<Invocation, String> {
-    let (dst_host, dst_port) = parse_host_port(&rule.remote_address)?;
+    let mut ssh_args: Vec<String> = vec![
+        // Keep running; port-forward only
+        "-N".to_string(),
+    ];
+    // ssh keeps the first value given for an option, so the user's come before
+    // the defaults below (e.g. "-o BatchMode=no" with an askpass helper).
+    ssh_args.extend(rule.ssh_extra_args.iter().cloned());
+    ssh_args.extend(session_options(rule));
+    if needs_gateway_ports(rule) {
+        // Add -g option to allow remote hosts to connect to local forwarded ports
+        ssh_args.push("-g".to_string());
+    }
+    ssh_args.extend(forward_args(rule)?);
+    ssh_args.extend(connection_args(rule)?);
 
-    let use_password = rule.ssh_password.is_some();
-    let mut ssh_args: Vec<String> = Vec::new();
+    Ok(Invocation {
+        program: "ssh".to_string(),
+        args: ssh_args,
+    })
+}
 
-    // Keep running; port-forward only
-    ssh_args.push("-N".to_string());
-    // Exit immediately if forwarding setup fails (so the supervisor can restart)
-    ssh_args.push("-o".to_string());
-    ssh_args.push("ExitOnForwardFailure=yes".to_string());
-    // KeepAlive: detect disconnects and exit promptly
-    ssh_args.push("-o".to_string());
-    ssh_args.push("ServerAliveInterval=30".to_string());
-    ssh_args.push("-o".to_string());
-    ssh_args.push("ServerAliveCountMax=3".to_string());
-    ssh_args.push("-o".to_string());
-    ssh_args.push("TCPKeepAlive=yes".to_string());
-    // Unified PTY mode: PTY can handle all interactive prompts (password, passphrase, host key, etc.)
-    // We don't use BatchMode since PTY handles all interactions.
-    // For password mode, limit password prompts to avoid infinite loops.
-    if use_password {
+/// ControlMaster for a host profile: authenticates once and carries no forwards
+/// itself; rules attach theirs with `build_control_invocation`.
+pub fn build_master_invocation(rule: &ForwardingRule, socket: &Path, gateway_ports: bool) -> Result<Invocation, String> {
+    let mut ssh_args: Vec<String> = vec![
+        "-N".to_string(),
+        "-M".to_string(),
+        "-S".to_string(),
+        socket.to_string_lossy().to_string(),
+        // The manager owns the master's lifetime; never leave it behind in the background.
+        "-o".to_string(),
+        "ControlPersist=no".to_string(),
+    ];
+    ssh_args.extend(rule.ssh_extra_args.iter().cloned());
+    ssh_args.extend(session_options(rule));
+    if gateway_ports {
+        // Applies to every forward added through this master.
+        ssh_args.push("-g".to_string());
+    }
+    ssh_args.extend(connection_args(rule)?);
+
+    Ok(Invocation {
+        program: "ssh".to_string(),
+        args: ssh_args,
+    })
+}
+
+/// `ssh -S <socket> -O <command>` against a running master: "check", or
+/// "forward" / "cancel" for the rule's -L / -R / -D spec.
+pub fn build_control_invocation(rule: &ForwardingRule, socket: &Path, command: &str) -> Result<Invocation, String> {
+    let mut ssh_args: Vec<String> = vec![
+        "-S".to_string(),
+        socket.to_string_lossy().to_string(),
+        "-O".to_string(),
+        command.to_string(),
+    ];
+    if command != "check" {
+        ssh_args.extend(forward_args(rule)?);
+    }
+    ssh_args.push(target(rule));
+
+    Ok(Invocation {
+        program: "ssh".to_string(),
+        args: ssh_args,
+    })
+}
+
+// Only needed when binding a local or dynamic forward to non-localhost addresses (e.g., 0.0.0.0)
+pub(crate) fn needs_gateway_ports(rule: &ForwardingRule) -> bool {
+    rule.kind != ForwardKind::Remote && rule.local_bind != "127.0.0.1" && rule.local_bind != "localhost"
+}
+
+// Options shared by every ssh session the manager starts (forwards and masters).
+fn session_options(rule: &ForwardingRule) -> Vec<String> {
+    let mut ssh_args: Vec<String> = vec![
+        // Exit immediately if forwarding setup fails (so the supervisor can restart)
+        "-o".to_string(),
+        "ExitOnForwardFailure=yes".to_string(),
+        // KeepAlive: detect disconnects and exit promptly
+        "-o".to_string(),
+        "ServerAliveInterval=30".to_string(),
+        "-o".to_string(),
+        "ServerAliveCountMax=3".to_string(),
+        "-o".to_string(),
+        "TCPKeepAlive=yes".to_string(),
+    ];
+    if rule.password().is_some() {
+        // PTY mode: the PTY runner answers the password / passphrase prompts.
+        // Limit password prompts to avoid infinite loops.
         ssh_args.push("-o".to_string());
         ssh_args.push("NumberOfPasswordPrompts=1".to_string());
+    } else {
+        // Process mode: nothing can answer prompts, so never ask. Key / agent auth
+        // either succeeds or fails fast with "Permission denied".
+        ssh_args.push("-o".to_string());
+        ssh_args.push("BatchMode=yes".to_string());
     }
     // Connection timeout
     ssh_args.push("-o".to_string());
     ssh_args.push("ConnectTimeout=10".to_string());
+    ssh_args
+}
 
-    // Add -g option to allow remote hosts to connect to local forwarded ports
-    // Only needed when binding to non-localhost addresses (e.g., 0.0.0.0)
-    if rule.local_bind != "127.0.0.1" && rule.local_bind != "localhost" {
-        ssh_args.push("-g".to_string());
-    }
-
-    let forward_spec = format!(
-        "{}:{}:{}:{}",
-        rule.local_bind, rule.local_port, dst_host, dst_port
-    );
-    ssh_args.push("-L".to_string());
-    ssh_args.push(forward_spec);
+// -L / -R / -D and its spec.
+fn forward_args(rule: &ForwardingRule) -> Result<Vec<String>, String> {
+    Ok(match rule.kind {
+        ForwardKind::Local => {
+            let (dst_host, dst_port) = parse_host_port(&rule.remote_address)?;
+            vec![
+                "-L".to_string(),
+                format!("{}:{}:{}:{}", rule.local_bind, rule.local_port, dst_host, dst_port),
+            ]
+        }
+        // remote_address is where the SSH server listens; connections come back to local_bind:local_port
+        ForwardKind::Remote => {
+            let (dst_host, dst_port) = parse_host_port(&rule.remote_address)?;
+            vec![
+                "-R".to_string(),
+                format!("{}:{}:{}:{}", dst_host, dst_port, rule.local_bind, rule.local_port),
+            ]
+        }
+        ForwardKind::Dynamic => vec!["-D".to_string(), format!("{}:{}", rule.local_bind, rule.local_port)],
+    })
+}
 
-    ssh_args.push("-p".to_string());
-    ssh_args.push(rule.ssh_port.to_string());
+// Port, identity and the user@host target.
+fn connection_args(rule: &ForwardingRule) -> Result<Vec<String>, String> {
+    let mut ssh_args: Vec<String> = vec!["-p".to_string(), rule.ssh_port.to_string()];
 
     if let Some(key_path) = &rule.ssh_key_path {
         let kp = expand_tilde_path(key_path);
@@ -93,15 +178,11 @@ pub fn build_invocation(rule: &ForwardingRule) -> Result<Invocation, String> {
         ssh_args.push(kp.to_string_lossy().to_string());
     }
 
-    // Pass through extra ssh args (e.g. -J / ProxyCommand / StrictHostKeyChecking)
-    ssh_args.extend(rule.ssh_extra_args.iter().cloned());
-
     // Target
-    ssh_args.push(format!("{}@{}", rule.ssh_user, rule.ssh_host));
-
-    Ok(Invocation {
-        program: "ssh".to_string(),
-        args: ssh_args,
-    })
+    ssh_args.push(target(rule));
+    Ok(ssh_args)
 }
 
+fn target(rule: &ForwardingRule) -> String {
+    format!("{}@{}", rule.ssh_user, rule.ssh_host)
+}
@@ -0,0 +1,448 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, watch};
+
+use crate::audit::format_timestamp;
+use crate::paths::{runtime_dir, stable_hash};
+use crate::supervisor::{Event, EventKind};
+
+// Lines of ssh output kept per rule for `logs` and the status API.
+const RECENT_OUTPUT_LINES: usize = 50;
+
+/// What a rule is doing right now.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleState {
+    /// On-demand rule waiting for its first client connection.
+    Idle,
+    /// Waiting for the rules in `depends_on` to connect.
+    Waiting,
+    /// Transport is being started.
+    Starting,
+    /// Transport session is running.
+    Running,
+    /// Waiting before the next connection attempt.
+    Backoff,
+    /// Credentials were rejected; not retrying.
+    AuthFailed,
+    /// Shut down.
+    Stopped,
+}
+
+impl RuleState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RuleState::Idle => "idle",
+            RuleState::Waiting => "waiting",
+            RuleState::Starting => "starting",
+            RuleState::Running => "running",
+            RuleState::Backoff => "backoff",
+            RuleState::AuthFailed => "auth_failed",
+            RuleState::Stopped => "stopped",
+        }
+    }
+}
+
+/// Request to a rule's supervisor (status API).
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Command {
+    /// Run again after `Stop`; retry right away while in backoff.
+    Start,
+    /// End the session and stay stopped until `Start` / `Restart`.
+    Stop,
+    /// End the session and reconnect right away.
+    Restart,
+}
+
+// What the supervisor should be doing, as last requested.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Control {
+    pub enabled: bool,
+    pub last: Option<Command>,
+}
+
+/// Connection and byte counters of a front-proxied rule.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TrafficStatus {
+    pub active_connections: u64,
+    pub total_connections: u64,
+    /// Client -> remote.
+    pub bytes_up: u64,
+    /// Remote -> client.
+    pub bytes_down: u64,
+    /// Summed / longest duration of closed connections, in milliseconds.
+    pub total_duration_ms: u64,
+    pub max_duration_ms: u64,
+    /// Connections refused because `max_connections` were already open.
+    pub rejected_over_limit: u64,
+    /// Reads delayed by `rate_limit_up` / `rate_limit_down`.
+    pub throttled: u64,
+}
+
+/// Point-in-time status of one rule.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RuleStatus {
+    pub name: String,
+    pub description: String,
+    pub state: RuleState,
+    /// Unix time (seconds) of the last state change.
+    pub since: u64,
+    pub restarts: u64,
+    /// Sessions ended by rejected credentials.
+    #[serde(default)]
+    pub auth_failures: u64,
+    /// Delay before the next attempt while in `backoff`, else 0.
+    #[serde(default)]
+    pub backoff_secs: u64,
+    /// Unix time (seconds) the tunnel last came up.
+    #[serde(default)]
+    pub last_connected: Option<u64>,
+    pub last_exit: Option<String>,
+    /// Only for rules behind the front proxy.
+    pub traffic: Option<TrafficStatus>,
+    /// Last lines of ssh output, oldest first, each prefixed with its time.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recent_output: Vec<String>,
+}
+
+/// Status of every rule of a running manager, as written to the status file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatusReport {
+    pub pid: u32,
+    /// Unix time (seconds) the report was written.
+    pub updated: u64,
+    pub rules: Vec<RuleStatus>,
+}
+
+#[derive(Default)]
+pub(crate) struct Traffic {
+    pub active: AtomicU64,
+    pub total: AtomicU64,
+    pub bytes_up: AtomicU64,
+    pub bytes_down: AtomicU64,
+    pub total_duration_ms: AtomicU64,
+    pub max_duration_ms: AtomicU64,
+    pub rejected_over_limit: AtomicU64,
+    pub throttled: AtomicU64,
+}
+
+impl Traffic {
+    pub fn connection_closed(&self, duration: Duration) {
+        let ms = duration.as_millis() as u64;
+        self.active.fetch_sub(1, Ordering::Relaxed);
+        self.total_duration_ms.fetch_add(ms, Ordering::Relaxed);
+        self.max_duration_ms.fetch_max(ms, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> TrafficStatus {
+        TrafficStatus {
+            active_connections: self.active.load(Ordering::Relaxed),
+            total_connections: self.total.load(Ordering::Relaxed),
+            bytes_up: self.bytes_up.load(Ordering::Relaxed),
+            bytes_down: self.bytes_down.load(Ordering::Relaxed),
+            total_duration_ms: self.total_duration_ms.load(Ordering::Relaxed),
+            max_duration_ms: self.max_duration_ms.load(Ordering::Relaxed),
+            rejected_over_limit: self.rejected_over_limit.load(Ordering::Relaxed),
+            throttled: self.throttled.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Last lines of a rule's ssh output, filled by its transport (see
+/// `Transport::record_output`). Lines arrive with the password already masked.
+#[derive(Default)]
+pub struct RecentOutput {
+    lines: Mutex<VecDeque<String>>,
+}
+
+impl RecentOutput {
+    pub fn push(&self, line: &str) {
+        let mut lines = self.lines.lock().unwrap();
+        if lines.len() == RECENT_OUTPUT_LINES {
+            lines.pop_front();
+        }
+        lines.push_back(format!("{} {}", format_timestamp(SystemTime::now()), line));
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        self.lines.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+struct Lifecycle {
+    state: RuleState,
+    since: SystemTime,
+    restarts: u64,
+    auth_failures: u64,
+    backoff: Duration,
+    connected: Option<SystemTime>,
+    last_exit: Option<String>,
+}
+
+// Live status of one rule, updated by its supervisor and front proxy.
+pub(crate) struct RuleHandle {
+    name: String,
+    description: String,
+    lifecycle: Mutex<Lifecycle>,
+    control: watch::Sender<Control>,
+    pub traffic: Option<Traffic>,
+    pub output: Arc<RecentOutput>,
+    events: broadcast::Sender<Event>,
+    // Names of the connected rules of the board, for `depends_on`.
+    connected: watch::Sender<BTreeSet<String>>,
+}
+
+impl RuleHandle {
+    pub fn set_state(&self, state: RuleState) {
+        let mut l = self.lifecycle.lock().unwrap();
+        // Every attempt starts with set_state(Starting), also the first one,
+        // where the state already is Starting.
+        if l.state == state && state != RuleState::Starting {
+            return;
+        }
+        l.state = state;
+        l.since = SystemTime::now();
+        match state {
+            RuleState::Running => l.connected = Some(l.since),
+            RuleState::AuthFailed => l.auth_failures += 1,
+            _ => {}
+        }
+        if state != RuleState::Backoff {
+            l.backoff = Duration::ZERO;
+        }
+        let running = state == RuleState::Running;
+        self.connected.send_if_modified(|names| {
+            if running {
+                names.insert(self.name.clone())
+            } else {
+                names.remove(&self.name)
+            }
+        });
+        let event = match state {
+            RuleState::Idle | RuleState::Waiting => return,
+            RuleState::Starting => EventKind::RuleStarting,
+            RuleState::Running => EventKind::Connected,
+            RuleState::Backoff => EventKind::BackoffScheduled { delay: l.backoff },
+            RuleState::AuthFailed => EventKind::AuthFailed,
+            RuleState::Stopped => EventKind::Stopped,
+        };
+        drop(l);
+        self.emit(event);
+    }
+
+    pub fn session_ended(&self, reason: &str) {
+        self.lifecycle.lock().unwrap().last_exit = Some(reason.to_string());
+        self.emit(EventKind::Disconnected {
+            reason: reason.to_string(),
+        });
+    }
+
+    // Fails only without receivers, which is fine.
+    fn emit(&self, kind: EventKind) {
+        let _ = self.events.send(Event {
+            rule: self.name.clone(),
+            kind,
+        });
+    }
+
+    // Followed by set_state(Backoff) for `backoff`.
+    pub fn restarting(&self, backoff: Duration) {
+        let mut l = self.lifecycle.lock().unwrap();
+        l.restarts += 1;
+        l.backoff = backoff;
+    }
+
+    // Names of the connected rules of the board, updated as they come and go.
+    pub fn connected_rules(&self) -> watch::Receiver<BTreeSet<String>> {
+        self.connected.subscribe()
+    }
+
+    // Commands sent after this call; see `command`.
+    pub fn control(&self) -> watch::Receiver<Control> {
+        self.control.subscribe()
+    }
+
+    pub fn command(&self, command: Command) -> Result<(), String> {
+        if self.lifecycle.lock().unwrap().state == RuleState::AuthFailed {
+            return Err("the rule stopped after an authentication failure; restart the manager".to_string());
+        }
+        self.control.send_modify(|c| {
+            c.enabled = command != Command::Stop;
+            c.last = Some(command);
+        });
+        Ok(())
+    }
+
+    pub fn snapshot(&self) -> RuleStatus {
+        let l = self.lifecycle.lock().unwrap();
+        RuleStatus {
+            name: self.name.clone(),
+            description: self.description.clone(),
+            state: l.state,
+            since: unix_secs(l.since),
+            restarts: l.restarts,
+            auth_failures: l.auth_failures,
+            backoff_secs: l.backoff.as_secs(),
+            last_connected: l.connected.map(unix_secs),
+            last_exit: l.last_exit.clone(),
+            traffic: self.traffic.as_ref().map(Traffic::snapshot),
+            recent_output: self.output.lines(),
+        }
+    }
+}
+
+// All rules of one manager.
+pub(crate) struct StatusBoard {
+    rules: Mutex<Vec<Arc<RuleHandle>>>,
+    events: broadcast::Sender<Event>,
+    connected: watch::Sender<BTreeSet<String>>,
+}
+
+impl StatusBoard {
+    pub fn new(events: broadcast::Sender<Event>) -> Self {
+        Self {
+            rules: Mutex::default(),
+            events,
+            connected: watch::Sender::default(),
+        }
+    }
+
+    pub fn register(&self, name: String, description: String, with_traffic: bool) -> Arc<RuleHandle> {
+        let handle = Arc::new(RuleHandle {
+            name,
+            description,
+            lifecycle: Mutex::new(Lifecycle {
+                state: RuleState::Starting,
+                since: SystemTime::now(),
+                restarts: 0,
+                auth_failures: 0,
+                backoff: Duration::ZERO,
+                connected: None,
+                last_exit: None,
+            }),
+            control: watch::Sender::new(Control {
+                enabled: true,
+                last: None,
+            }),
+            traffic: with_traffic.then(Traffic::default),
+            output: Arc::default(),
+            events: self.events.clone(),
+            connected: self.connected.clone(),
+        });
+        self.rules.lock().unwrap().push(handle.clone());
+        handle
+    }
+
+    pub fn remove(&self, name: &str) {
+        self.rules.lock().unwrap().retain(|r| r.name != name);
+    }
+
+    pub fn find(&self, name: &str) -> Option<Arc<RuleHandle>> {
+        self.rules.lock().unwrap().iter().find(|r| r.name == name).cloned()
+    }
+
+    pub fn report(&self) -> StatusReport {
+        StatusReport {
+            pid: std::process::id(),
+            updated: unix_secs(SystemTime::now()),
+            rules: self.rules.lock().unwrap().iter().map(|r| r.snapshot()).collect(),
+        }
+    }
+
+    // Replace the status file atomically, so readers never see a partial write.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_vec_pretty(&self.report()).map_err(io::Error::other)?;
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, json)?;
+        fs::rename(&tmp, path)
+    }
+}
+
+/// Where the manager running `config_path` writes its status; one file per config.
+pub fn status_file(config_path: &str) -> io::Result<PathBuf> {
+    let canonical = fs::canonicalize(config_path)?;
+    let hash = stable_hash(canonical.as_os_str().as_encoded_bytes());
+    Ok(runtime_dir()?.join(format!("status-{:016x}.json", hash)))
+}
+
+/// Read the status written by the manager running `config_path`.
+pub fn read_status(config_path: &str) -> io::Result<StatusReport> {
+    let data = fs::read(status_file(config_path)?)?;
+    serde_json::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Render a status report as a table, one line per rule.
+pub fn format_status(report: &StatusReport) -> String {
+    let now = unix_secs(SystemTime::now());
+    let mut out = format!(
+        "{:<24} {:<12} {:>8} {:>8} {:>7} {:>7} {:>10} {:>10} {:>9} {:>11}\n",
+        "NAME", "STATE", "FOR", "RESTARTS", "ACTIVE", "CONNS", "UP", "DOWN", "AVG CONN", "LIMIT HITS"
+    );
+    for r in &report.rules {
+        let age = format_duration(now.saturating_sub(r.since));
+        let traffic = match &r.traffic {
+            Some(t) => {
+                let closed = t.total_connections.saturating_sub(t.active_connections);
+                let avg = match closed {
+                    0 => "-".to_string(),
+                    n => format_duration(t.total_duration_ms / n / 1000),
+                };
+                format!(
+                    "{:>7} {:>7} {:>10} {:>10} {:>9} {:>11}",
+                    t.active_connections,
+                    t.total_connections,
+                    format_bytes(t.bytes_up),
+                    format_bytes(t.bytes_down),
+                    avg,
+                    format!("{}/{}", t.rejected_over_limit, t.throttled)
+                )
+            }
+            None => format!("{:>7} {:>7} {:>10} {:>10} {:>9} {:>11}", "-", "-", "-", "-", "-", "-"),
+        };
+        out.push_str(&format!(
+            "{:<24} {:<12} {:>8} {:>8} {}\n",
+            r.name,
+            r.state.as_str(),
+            age,
+            r.restarts,
+            traffic
+        ));
+    }
+    out
+}
+
+pub(crate) fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn format_duration(secs: u64) -> String {
+    match secs {
+        s if s < 60 => format!("{}s", s),
+        s if s < 3600 => format!("{}m{}s", s / 60, s % 60),
+        s if s < 86400 => format!("{}h{}m", s / 3600, s % 3600 / 60),
+        s => format!("{}d{}h", s / 86400, s % 86400 / 3600),
+    }
+}
+
+fn format_bytes(n: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = n as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", n)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
@@ -1,106 +1,407 @@
-use std::{io, sync::mpsc, time::Instant};
+use std::collections::BTreeSet;
+use std::io;
+use std::sync::Arc;
 
+use serde::Serialize;
 use tokio::sync::watch;
-use tokio::time::{sleep, Duration};
+use tokio::time::{sleep, Duration, Instant};
+use tracing::{error, info, info_span, warn, Instrument};
 
-use crate::config::{Config, ForwardingRule};
-use crate::runner::run_ssh_with_pty;
-use crate::ssh_args::{build_invocation, Invocation};
+use crate::config::{Backend, ForwardKind, ForwardingRule};
+use crate::hooks::{Hook, Hooks};
+use crate::status::{Command, Control, RuleHandle, RuleState};
+use crate::transport::{self, ExitReason, Transport};
+
+/// Lifecycle event of one rule, see `TunnelManager::subscribe`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Event {
+    /// Rule name, as in the status output.
+    pub rule: String,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind {
+    /// A connection attempt begins.
+    RuleStarting,
+    /// The tunnel is up, i.e. usable (see `Transport::ready`); the rule is `Running`.
+    Connected,
+    /// The session ended (or failed to start).
+    Disconnected { reason: String },
+    /// The next attempt follows after `delay`.
+    BackoffScheduled {
+        #[serde(rename = "delay_secs", serialize_with = "as_secs")]
+        delay: Duration,
+    },
+    /// Credentials were rejected; the rule is not retried.
+    AuthFailed,
+    /// The rule stopped (shutdown, or a stop request).
+    Stopped,
+}
+
+fn as_secs<S: serde::Serializer>(delay: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(delay.as_secs())
+}
 
 // format rule full information, for logging
-fn format_rule_full(rule: &ForwardingRule) -> String {
-    format!(
-        "local {}:{} -> {} via {}@{}:{}",
-        rule.local_bind,
-        rule.local_port,
-        rule.remote_address,
-        rule.ssh_user,
-        rule.ssh_host,
-        rule.ssh_port
-    )
-}
-
-// Supervise a single forwarding rule: run ssh, auto-restart on disconnect, stop on auth failure or shutdown.
-pub async fn supervise_ssh(rule: ForwardingRule, mut shutdown: watch::Receiver<bool>) -> io::Result<()> {
-    // Build ssh command-line invocation from rule config once (rule doesn't change in the loop).
-    let inv = match build_invocation(&rule) {
-        Ok(i) => i,
+pub(crate) fn format_rule_full(rule: &ForwardingRule) -> String {
+    let backend = match rule.backend {
+        Backend::Ssh => "",
+        Backend::Native => " (native)",
+    };
+    match rule.kind {
+        ForwardKind::Local => format!(
+            "local {}:{} -> {} via {}@{}:{}{}",
+            rule.local_bind,
+            rule.local_port,
+            rule.remote_address,
+            rule.ssh_user,
+            rule.ssh_host,
+            rule.ssh_port,
+            backend
+        ),
+        ForwardKind::Remote => format!(
+            "remote {} -> {}:{} via {}@{}:{}{}",
+            rule.remote_address,
+            rule.local_bind,
+            rule.local_port,
+            rule.ssh_user,
+            rule.ssh_host,
+            rule.ssh_port,
+            backend
+        ),
+        ForwardKind::Dynamic => format!(
+            "dynamic {}:{} (SOCKS) via {}@{}:{}{}",
+            rule.local_bind, rule.local_port, rule.ssh_user, rule.ssh_host, rule.ssh_port, backend
+        ),
+    }
+}
+
+// Supervise a single forwarding rule with its built-in transport (see `transport::for_rule`).
+pub async fn supervise_ssh(rule: ForwardingRule, shutdown: watch::Receiver<bool>) -> io::Result<()> {
+    let transport = match transport::for_rule(&rule) {
+        Ok(t) => t,
         Err(e) => {
-            eprintln!("Config error for {}: {}", format_rule_full(&rule), e);
+            error!(rule = %rule.name(), "Config error for {}: {}", format_rule_full(&rule), e);
             return Err(io::Error::new(io::ErrorKind::InvalidInput, e));
         }
     };
+    supervise(rule, transport, shutdown).await
+}
+
+// Supervise a single forwarding rule: run the transport, auto-restart on disconnect,
+// stop on auth failure or shutdown.
+pub async fn supervise(
+    rule: ForwardingRule,
+    mut transport: Box<dyn Transport>,
+    shutdown: watch::Receiver<bool>,
+) -> io::Result<()> {
+    let span = rule_span(&rule);
+    supervise_subject(&rule_subject(&rule, None), transport.as_mut(), shutdown)
+        .instrument(span)
+        .await
+}
+
+// Everything a rule's task logs, including its transport and front proxy, runs
+// in this span, so events carry the rule name.
+pub(crate) fn rule_span(rule: &ForwardingRule) -> tracing::Span {
+    info_span!("tunnel", rule = %rule.name())
+}
+
+pub(crate) fn rule_subject(rule: &ForwardingRule, status: Option<Arc<RuleHandle>>) -> Subject {
+    Subject {
+        kind: "ssh forward",
+        full: format_rule_full(rule),
+        short: match rule.kind {
+            ForwardKind::Dynamic => format!("{}:{} (SOCKS)", rule.local_bind, rule.local_port),
+            _ => format!("{}:{} -> {}", rule.local_bind, rule.local_port, rule.remote_address),
+        },
+        status,
+        hooks: Hooks::for_rule(rule),
+        depends_on: rule.depends_on.clone(),
+    }
+}
+
+// What a restart loop is running, for logging, status and hooks.
+pub(crate) struct Subject {
+    pub kind: &'static str,
+    pub full: String,
+    pub short: String,
+    pub status: Option<Arc<RuleHandle>>,
+    pub hooks: Option<Hooks>,
+    // Only followed with a status handle (rules of a manager).
+    pub depends_on: Vec<String>,
+}
+
+impl Subject {
+    fn set_state(&self, state: RuleState) {
+        if let Some(status) = &self.status {
+            status.set_state(state);
+        }
+    }
+
+    // A session that came up has ended: status, events and the on_down hook.
+    fn session_ended(&self, reason: &str, attempt: u32) {
+        self.start_failed(reason);
+        self.hook(Hook::Down, attempt, Some(reason));
+    }
+
+    fn start_failed(&self, reason: &str) {
+        if let Some(status) = &self.status {
+            status.session_ended(reason);
+        }
+    }
+
+    fn hook(&self, hook: Hook, attempt: u32, reason: Option<&str>) {
+        if let Some(hooks) = &self.hooks {
+            hooks.run(hook, attempt, reason);
+        }
+    }
+
+    // Let the hooks started so far (e.g. the last on_down) finish before the task ends.
+    async fn hooks_done(&self) {
+        if let Some(hooks) = &self.hooks {
+            hooks.finish().await;
+        }
+    }
+}
+
+// Next command from the status API; never resolves for subjects without a status handle.
+async fn next_command(control: &mut Option<watch::Receiver<Control>>) -> Control {
+    let Some(rx) = control else {
+        return std::future::pending().await;
+    };
+    if rx.changed().await.is_err() {
+        return std::future::pending().await;
+    }
+    *rx.borrow_and_update()
+}
+
+// Next stop / restart request; a start request does not affect a running session.
+async fn interrupted(control: &mut Option<watch::Receiver<Control>>) -> Command {
+    loop {
+        if let Some(command @ (Command::Stop | Command::Restart)) = next_command(control).await.last {
+            return command;
+        }
+    }
+}
+
+fn stopped(control: &Option<watch::Receiver<Control>>) -> bool {
+    control.as_ref().is_some_and(|c| !c.borrow().enabled)
+}
+
+// First rule of `depends_on` that is not connected.
+fn waiting_for<'a>(subject: &'a Subject, connected: &Option<watch::Receiver<BTreeSet<String>>>) -> Option<&'a str> {
+    let connected = connected.as_ref()?.borrow();
+    subject.depends_on.iter().find(|d| !connected.contains(*d)).map(String::as_str)
+}
+
+// Next change of the connected rules; never resolves for subjects without dependencies.
+async fn connected_changed(connected: &mut Option<watch::Receiver<BTreeSet<String>>>) {
+    let Some(rx) = connected else {
+        return std::future::pending().await;
+    };
+    if rx.changed().await.is_err() {
+        std::future::pending().await
+    }
+}
+
+async fn dependencies_up(subject: &Subject, connected: &mut Option<watch::Receiver<BTreeSet<String>>>) {
+    while waiting_for(subject, connected).is_some() {
+        connected_changed(connected).await;
+    }
+}
+
+// Name of the first dependency to go down.
+async fn dependency_lost(subject: &Subject, connected: &mut Option<watch::Receiver<BTreeSet<String>>>) -> String {
+    loop {
+        if let Some(name) = waiting_for(subject, connected) {
+            return name.to_string();
+        }
+        connected_changed(connected).await;
+    }
+}
 
+pub(crate) async fn supervise_subject(
+    subject: &Subject,
+    transport: &mut dyn Transport,
+    mut shutdown: watch::Receiver<bool>,
+) -> io::Result<()> {
     let mut attempt: u32 = 0;
+    let mut control = subject.status.as_ref().map(|s| s.control());
+    let mut connected = subject
+        .status
+        .as_ref()
+        .filter(|_| !subject.depends_on.is_empty())
+        .map(|s| s.connected_rules());
 
     // Restart loop: reconnect on failure with exponential backoff (max 20s).
-    loop {
+    'restart: loop {
         if *shutdown.borrow() {
             break;
         }
+        // Stopped through the status API: wait for start / restart.
+        if stopped(&control) {
+            subject.set_state(RuleState::Stopped);
+            while stopped(&control) {
+                tokio::select! {
+                    _ = next_command(&mut control) => {}
+                    _ = shutdown.changed() => break 'restart,
+                }
+            }
+            attempt = 0;
+        }
+        // depends_on: start only once every dependency is connected.
+        if let Some(name) = waiting_for(subject, &connected) {
+            info!(dependency = %name, "Waiting for {} before starting {}", name, subject.short);
+            subject.set_state(RuleState::Waiting);
+            tokio::select! {
+                _ = dependencies_up(subject, &mut connected) => {}
+                _ = shutdown.changed() => break,
+                _ = next_command(&mut control) => {}
+            }
+            attempt = 0;
+            continue;
+        }
 
-        println!("Starting ssh forward: {}", format_rule_full(&rule));
-
-        // Unified PTY mode: works for both password and non-password modes.
-        let password = rule.ssh_password.clone().filter(|s| !s.is_empty());
-        let (kill_tx, kill_rx) = mpsc::channel::<()>();
-        let inv2 = Invocation {
-            program: inv.program.clone(),
-            args: inv.args.clone(),
-        };
-
-        // PTY operations are blocking; run on a blocking task.
-        let mut handle = tokio::task::spawn_blocking(move || {
-            run_ssh_with_pty(&inv2, password.as_deref(), kill_rx)
-        });
+        info!(attempt, "Starting {}: {}", subject.kind, subject.full);
+        subject.set_state(RuleState::Starting);
 
         // Record start time to determine if connection was successfully established
         let start_time = Instant::now();
         // Wait for ssh to exit or shutdown signal; stop retrying on auth failure.
         let mut should_reset_attempt = false;
 
-        // Note: If SSH process runs successfully, select! will wait
-        tokio::select! {
-            res = &mut handle => {
-                match res {
-                    // double result: spawn_blocking exit ok, run_ssh_with_pty exit ok
-                    Ok(Ok(exit)) => {
-                        let elapsed = start_time.elapsed();
-                        eprintln!(
-                            "ssh exited ({}:{} -> {}): code={}, elapsed={:?}",
-                            rule.local_bind, rule.local_port, rule.remote_address, exit.code, elapsed
-                        );
-                        // Auth failure: stop retrying this rule to avoid log spam.
-                        if exit.auth_failed {
-                            eprintln!(
-                                "Authentication failed for {}; not retrying.",
-                                format_rule_full(&rule)
-                            );
-                            return Ok(());
-                        }
-                        // Reset attempt if process ran for at least 5 seconds (connection was established before disconnect)
-                        if elapsed.as_secs() >= 5 {
-                            should_reset_attempt = true;
-                        }
+        // start() may wait on something else (e.g. a shared control master), so
+        // it has to give way to shutdown as well.
+        let started = tokio::select! {
+            res = transport.start() => res,
+            _ = shutdown.changed() => {
+                transport.kill().await;
+                break;
+            }
+            command = interrupted(&mut control) => {
+                info!(?command, "{:?} requested for {}", command, subject.short);
+                transport.kill().await;
+                attempt = 0;
+                continue;
+            }
+        };
+        match started {
+            Ok(()) => {
+                // Still starting until the session is usable (e.g. the forward listens).
+                let ready = tokio::select! {
+                    res = transport.ready() => res,
+                    _ = shutdown.changed() => {
+                        transport.kill().await;
+                        break;
+                    }
+                    name = dependency_lost(subject, &mut connected) => {
+                        warn!(dependency = %name, "{} is down; stopping {}", name, subject.short);
+                        transport.kill().await;
+                        subject.start_failed(&format!("dependency {} down", name));
+                        attempt = 0;
+                        continue;
                     }
-                    Ok(Err(e)) => {
-                        eprintln!(
-                            "ssh pty error ({}:{} -> {}): {}",
-                            rule.local_bind, rule.local_port, rule.remote_address, e
+                    command = interrupted(&mut control) => {
+                        info!(?command, "{:?} requested for {}", command, subject.short);
+                        transport.kill().await;
+                        attempt = 0;
+                        continue;
+                    }
+                };
+                let reason = match ready {
+                    Ok(()) => {
+                        info!(
+                            elapsed_ms = start_time.elapsed().as_millis() as u64,
+                            "{} is up ({})",
+                            subject.kind,
+                            subject.short
                         );
+                        subject.set_state(RuleState::Running);
+                        subject.hook(Hook::Up, attempt, None);
+                        // Note: If SSH runs successfully, select! will wait
+                        tokio::select! {
+                            reason = transport.wait() => {
+                                let elapsed = start_time.elapsed();
+                                warn!(
+                                    attempt,
+                                    elapsed_ms = elapsed.as_millis() as u64,
+                                    reason = %reason,
+                                    "ssh exited ({})",
+                                    subject.short
+                                );
+                                subject.session_ended(&reason.to_string(), attempt);
+                                // Reset attempt if ssh ran for at least 5 seconds (connection was established before disconnect)
+                                if matches!(reason, ExitReason::Exited { .. }) && elapsed.as_secs() >= 5 {
+                                    should_reset_attempt = true;
+                                }
+                                reason
+                            }
+                            _ = shutdown.changed() => {
+                                transport.kill().await;
+                                subject.hook(Hook::Down, attempt, Some("stopped"));
+                                break;
+                            }
+                            // A dependency went down: wait for it again, no backoff.
+                            name = dependency_lost(subject, &mut connected) => {
+                                warn!(dependency = %name, "{} is down; stopping {}", name, subject.short);
+                                transport.kill().await;
+                                subject.session_ended(&format!("dependency {} down", name), attempt);
+                                attempt = 0;
+                                continue;
+                            }
+                            // Requested through the status API: no backoff either way.
+                            command = interrupted(&mut control) => {
+                                info!(
+                                    ?command,
+                                    elapsed_ms = start_time.elapsed().as_millis() as u64,
+                                    "{:?} requested for {}",
+                                    command,
+                                    subject.short
+                                );
+                                transport.kill().await;
+                                subject.session_ended(&format!("{:?} requested", command).to_lowercase(), attempt);
+                                attempt = 0;
+                                continue;
+                            }
+                        }
                     }
-                    Err(e) => {
-                        eprintln!(
-                            "ssh pty task join error ({}:{} -> {}): {}",
-                            rule.local_bind, rule.local_port, rule.remote_address, e
+                    // Ended before it was up: a failed attempt, no on_down.
+                    Err(_) => {
+                        let reason = transport.wait().await;
+                        warn!(
+                            attempt,
+                            elapsed_ms = start_time.elapsed().as_millis() as u64,
+                            reason = %reason,
+                            "ssh exited before the tunnel was up ({})",
+                            subject.short
                         );
+                        subject.start_failed(&reason.to_string());
+                        reason
                     }
+                };
+                // Auth failure: stop retrying this rule to avoid log spam.
+                if reason == ExitReason::AuthFailed {
+                    error!("Authentication failed for {}; not retrying.", subject.full);
+                    subject.set_state(RuleState::AuthFailed);
+                    subject.hook(Hook::AuthFailure, attempt, Some(&reason.to_string()));
+                    subject.hooks_done().await;
+                    return Ok(());
                 }
             }
-            _ = shutdown.changed() => {
-                let _ = kill_tx.send(());
-                let _ = handle.await;
-                break;
+            Err(e) => {
+                warn!(
+                    attempt,
+                    elapsed_ms = start_time.elapsed().as_millis() as u64,
+                    reason = "start error",
+                    error = %e,
+                    "ssh start error ({})",
+                    subject.short
+                );
+                subject.start_failed(&format!("start error: {}", e));
             }
         }
 
@@ -117,61 +418,175 @@ pub async fn supervise_ssh(rule: ForwardingRule, mut shutdown: watch::Receiver<b
             attempt = attempt.saturating_add(1);
         }
         let backoff = Duration::from_secs((attempt.min(10) as u64).saturating_mul(2).max(1));
-        eprintln!(
-            "Restarting in {:?} ({}:{} -> {})",
-            backoff, rule.local_bind, rule.local_port, rule.remote_address
-        );
-        sleep(backoff).await;
+        warn!(attempt, backoff_secs = backoff.as_secs(), "Restarting in {:?} ({})", backoff, subject.short);
+        if let Some(status) = &subject.status {
+            status.restarting(backoff);
+        }
+        subject.set_state(RuleState::Backoff);
+        // Shutdown must not wait for the backoff to elapse; any API command ends it too.
+        tokio::select! {
+            _ = sleep(backoff) => {}
+            _ = shutdown.changed() => break,
+            _ = next_command(&mut control) => {}
+        }
     }
 
+    subject.set_state(RuleState::Stopped);
+    subject.hooks_done().await;
     Ok(())
 }
 
-// Main entry point: start one supervisor task per forwarding rule, handle Ctrl-C gracefully.
-pub async fn run(config: Config) -> io::Result<()> {
-    println!("Loaded {} forwarding rule(s)", config.forwarding.len());
+#[cfg(test)]
+mod tests {
+    use tokio::sync::broadcast;
 
-    // watch::channel broadcasts shutdown signal to all supervisor tasks.
-    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    use super::*;
+    use crate::status::{RuleStatus, StatusBoard};
+    use crate::transport::BoxFuture;
 
-    // Start and supervise one persistent ssh process per rule
-    let mut join_set = tokio::task::JoinSet::new();
-    for rule in config.forwarding.into_iter() {
-        let rx = shutdown_rx.clone();
-        join_set.spawn(async move {
-            if let Err(e) = supervise_ssh(rule, rx).await {
-                eprintln!("forwarding task error: {}", e);
-            }
-        });
+    // Becomes ready after `ready_after` and runs until killed, or, if it never
+    // comes up, ends at that point.
+    struct SlowTransport {
+        ready_after: Duration,
+        comes_up: bool,
     }
 
-    // Exit on Ctrl-C OR when all forwarding tasks finish (e.g. auth failure + no-retry).
-    loop {
-        tokio::select! {
-            // Ctrl-C: broadcast shutdown, wait for all tasks to finish, then exit.
-            _ = tokio::signal::ctrl_c() => {
-                println!("Shutting down...");
-                let _ = shutdown_tx.send(true);
-                while let Some(_res) = join_set.join_next().await {
-                    // drain
+    impl Transport for SlowTransport {
+        fn start(&mut self) -> BoxFuture<'_, io::Result<()>> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn ready(&mut self) -> BoxFuture<'_, io::Result<()>> {
+            Box::pin(async move {
+                sleep(self.ready_after).await;
+                if self.comes_up {
+                    Ok(())
+                } else {
+                    Err(io::Error::other("ended"))
                 }
-                break;
-            }
-            // One task finished (e.g., auth failure); keep waiting for others or Ctrl-C.
-            res = join_set.join_next() => {
-                match res {
-                    Some(_res) => {
-                        // one task finished; keep waiting for others or Ctrl-C
-                    }
-                    None => {
-                        println!("All forwarding tasks finished; exiting.");
-                        break;
-                    }
+            })
+        }
+
+        fn wait(&mut self) -> BoxFuture<'_, ExitReason> {
+            Box::pin(async move {
+                if self.comes_up {
+                    std::future::pending().await
+                } else {
+                    ExitReason::Exited { code: 255 }
                 }
-            }
+            })
+        }
+
+        fn kill(&mut self) -> BoxFuture<'_, ()> {
+            Box::pin(async {})
         }
     }
 
-    Ok(())
-}
+    // Status and events of a rule on `transport` after `after`.
+    async fn observe(
+        mut transport: SlowTransport,
+        hooks: Option<Hooks>,
+        after: Duration,
+    ) -> (RuleStatus, Vec<EventKind>) {
+        let (events_tx, mut events_rx) = broadcast::channel(64);
+        let board = StatusBoard::new(events_tx);
+        let subject = Subject {
+            kind: "ssh forward",
+            full: "db".to_string(),
+            short: "db".to_string(),
+            status: Some(board.register("db".to_string(), String::new(), false)),
+            hooks,
+            depends_on: Vec::new(),
+        };
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let observer = async {
+            sleep(after).await;
+            let status = subject.status.as_ref().unwrap().snapshot();
+            let events = std::iter::from_fn(|| events_rx.try_recv().ok()).map(|e| e.kind).collect();
+            shutdown_tx.send(true).unwrap();
+            (status, events)
+        };
+        let (res, seen) = tokio::join!(supervise_subject(&subject, &mut transport, shutdown_rx), observer);
+        res.unwrap();
+        seen
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connected_once_ready() {
+        let slow = || SlowTransport {
+            ready_after: Duration::from_secs(3),
+            comes_up: true,
+        };
 
+        let (status, events) = observe(slow(), None, Duration::from_secs(1)).await;
+        assert_eq!(status.state, RuleState::Starting);
+        assert_eq!(status.last_connected, None);
+        assert_eq!(events, vec![EventKind::RuleStarting]);
+
+        let (status, events) = observe(slow(), None, Duration::from_secs(4)).await;
+        assert_eq!(status.state, RuleState::Running);
+        assert!(status.last_connected.is_some());
+        assert_eq!(events, vec![EventKind::RuleStarting, EventKind::Connected]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn never_connected_without_ready() {
+        let never = SlowTransport {
+            ready_after: Duration::from_secs(10),
+            comes_up: false,
+        };
+
+        let (status, events) = observe(never, None, Duration::from_secs(11)).await;
+        assert_eq!(status.state, RuleState::Backoff);
+        assert_eq!(status.last_connected, None);
+        assert_eq!(
+            events,
+            vec![
+                EventKind::RuleStarting,
+                EventKind::Disconnected {
+                    reason: "exited, code=255".to_string()
+                },
+                EventKind::BackoffScheduled {
+                    delay: Duration::from_secs(2)
+                },
+            ]
+        );
+    }
+
+    // Hooks that append their event to a file.
+    #[cfg(unix)]
+    fn recording_hooks(path: &std::path::Path) -> Option<Hooks> {
+        let rule: ForwardingRule = toml::from_str(&format!(
+            r#"
+local_port = 15432
+remote_address = "db.internal:5432"
+ssh_host = "bastion.example.com"
+ssh_user = "tester"
+on_up = "echo $SSH_TUNNEL_EVENT >> {0}"
+on_down = "echo $SSH_TUNNEL_EVENT >> {0}"
+"#,
+            path.display()
+        ))
+        .unwrap();
+        Hooks::for_rule(&rule)
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn on_up_once_ready() {
+        let path = std::env::temp_dir().join(format!("ssh-tunnel-manager-hooks-{}", std::process::id()));
+        let transport = |comes_up| SlowTransport {
+            ready_after: Duration::from_millis(200),
+            comes_up,
+        };
+
+        // Ended before it was ready: neither on_up nor on_down.
+        observe(transport(false), recording_hooks(&path), Duration::from_millis(300)).await;
+        assert!(!path.exists());
+
+        observe(transport(true), recording_hooks(&path), Duration::from_millis(300)).await;
+        let ran = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(ran, "up\ndown\n");
+    }
+}
@@ -0,0 +1,308 @@
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout, Duration, Instant};
+use tracing::Instrument;
+
+use crate::config::{Backend, ForwardKind, ForwardingRule};
+#[cfg(feature = "native-ssh")]
+use crate::runner::{check_native_args, run_native};
+use crate::runner::{run_ssh_process, run_ssh_with_pty, SshExit};
+use crate::ssh_args::{build_invocation, Invocation};
+use crate::status::RecentOutput;
+
+// How often `SshTransport::ready` looks at the session.
+const READY_INTERVAL: Duration = Duration::from_millis(200);
+// Remote forwards cannot be probed from here: count ssh as up once it has
+// outlived ConnectTimeout (10s) plus a few seconds to authenticate and request
+// the forward. With ExitOnForwardFailure a refused forward ends it before that.
+const REMOTE_SETTLE: Duration = Duration::from_secs(15);
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Builds the transport for a rule (e.g. `for_rule`), for callers that need a
+/// new one when the rule changes between sessions.
+pub type TransportFactory = Box<dyn FnMut(&ForwardingRule) -> Result<Box<dyn Transport>, String> + Send>;
+
+/// Why a transport session ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExitReason {
+    /// Ended by `Transport::kill`.
+    Killed,
+    /// Connection ended (network error, server closed, forward failed); will be restarted.
+    Exited { code: i32 },
+    /// Credentials were rejected; the supervisor stops retrying this rule.
+    AuthFailed,
+    /// The transport itself failed (spawn error, task panic, ...); will be restarted.
+    Error(String),
+}
+
+impl fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExitReason::Killed => write!(f, "killed"),
+            ExitReason::Exited { code } => write!(f, "exited, code={}", code),
+            ExitReason::AuthFailed => write!(f, "authentication failed"),
+            ExitReason::Error(e) => write!(f, "error: {}", e),
+        }
+    }
+}
+
+/// One way of running a rule's SSH connection, driven by the supervisor's
+/// restart / backoff loop. A transport runs at most one session at a time.
+pub trait Transport: Send {
+    /// Start a new session (connection attempt).
+    fn start(&mut self) -> BoxFuture<'_, io::Result<()>>;
+    /// Wait until the started session is usable, e.g. its forward accepts
+    /// connections; the rule only counts as running from then on. Err means the
+    /// session ended first, and `wait` tells why. Must be cancel-safe like `wait`.
+    /// By default a session is usable as soon as `start` returns.
+    fn ready(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+    /// Wait until the running session ends. Must be cancel-safe: the supervisor
+    /// drops this future on shutdown and calls `kill` instead.
+    fn wait(&mut self) -> BoxFuture<'_, ExitReason>;
+    /// Stop the running session and wait until it has ended.
+    fn kill(&mut self) -> BoxFuture<'_, ()>;
+    /// Keep the ssh output of later sessions in `output`. Transports without
+    /// output of their own ignore it.
+    fn record_output(&mut self, _output: Arc<RecentOutput>) {}
+}
+
+/// Pick the built-in transport for a rule: the native client for `backend = "native"`,
+/// otherwise system ssh under a PTY when there are prompts to answer (`ssh_password`),
+/// or as a plain child process (BatchMode=yes).
+pub fn for_rule(rule: &ForwardingRule) -> Result<Box<dyn Transport>, String> {
+    // Also validates the rule (addresses, key path) for the native backend.
+    let inv = build_invocation(rule)?;
+    if rule.backend == Backend::Native {
+        if rule.kind == ForwardKind::Dynamic {
+            return Err("kind = \"dynamic\" is not supported by backend = \"native\"".to_string());
+        }
+        #[cfg(feature = "native-ssh")]
+        check_native_args(rule)?;
+        #[cfg(feature = "native-ssh")]
+        return Ok(Box::new(SshTransport::native(rule.clone())));
+        #[cfg(not(feature = "native-ssh"))]
+        return Err("backend = \"native\" requires building with `--features native-ssh`".to_string());
+    }
+    let transport = match rule.password() {
+        Some(pw) => SshTransport::pty(inv, pw.to_string()),
+        None => SshTransport::process(inv),
+    };
+    Ok(Box::new(match rule.kind {
+        ForwardKind::Local | ForwardKind::Dynamic => transport.ready_when_listening(&rule.local_bind, rule.local_port),
+        ForwardKind::Remote => transport,
+    }))
+}
+
+// What makes a session ready.
+enum Readiness {
+    // Its local listener accepts connections.
+    Listening(String, u16),
+    // It has been running for this long.
+    Settled(Duration),
+    // The runner says so (native client).
+    #[cfg(feature = "native-ssh")]
+    Signalled,
+}
+
+enum Runner {
+    Process(Invocation),
+    Pty(Invocation, String),
+    #[cfg(feature = "native-ssh")]
+    Native(Box<ForwardingRule>),
+}
+
+// A runner session on its own task plus the channel that kills it.
+struct RunnerTask {
+    handle: JoinHandle<io::Result<SshExit>>,
+    kill_tx: Option<oneshot::Sender<()>>,
+    started: Instant,
+    // Native runner only: fires once the forward is set up.
+    #[cfg_attr(not(feature = "native-ssh"), allow(dead_code))]
+    ready_rx: Option<oneshot::Receiver<()>>,
+}
+
+/// The built-in transports: system ssh (plain process or PTY) or the native client.
+pub struct SshTransport {
+    runner: Runner,
+    readiness: Readiness,
+    running: Option<RunnerTask>,
+    output: Option<Arc<RecentOutput>>,
+}
+
+impl SshTransport {
+    /// System ssh as a plain child process; the invocation should use BatchMode=yes.
+    pub fn process(inv: Invocation) -> Self {
+        Self::new(Runner::Process(inv))
+    }
+
+    /// System ssh under a PTY, answering password / passphrase prompts with `password`.
+    pub fn pty(inv: Invocation, password: String) -> Self {
+        Self::new(Runner::Pty(inv, password))
+    }
+
+    /// Built-in SSH client (russh), no system ssh binary involved.
+    #[cfg(feature = "native-ssh")]
+    pub fn native(rule: ForwardingRule) -> Self {
+        Self {
+            readiness: Readiness::Signalled,
+            ..Self::new(Runner::Native(Box::new(rule)))
+        }
+    }
+
+    /// Count a session as ready once `bind:port` accepts connections (the local
+    /// end of a -L / -D forward), instead of after a fixed settle time.
+    pub fn ready_when_listening(mut self, bind: &str, port: u16) -> Self {
+        self.readiness = Readiness::Listening(probe_host(bind).to_string(), port);
+        self
+    }
+
+    fn new(runner: Runner) -> Self {
+        Self {
+            runner,
+            readiness: Readiness::Settled(REMOTE_SETTLE),
+            running: None,
+            output: None,
+        }
+    }
+
+    /// Whether the last started session has ended (or none was started).
+    pub(crate) fn ended(&self) -> bool {
+        self.running.as_ref().is_none_or(|task| task.handle.is_finished())
+    }
+}
+
+// Where to connect to reach a listener bound to `bind`.
+fn probe_host(bind: &str) -> &str {
+    match bind {
+        "" | "*" | "0.0.0.0" => "127.0.0.1",
+        "::" | "[::]" => "::1",
+        _ => bind.trim_start_matches('[').trim_end_matches(']'),
+    }
+}
+
+impl Transport for SshTransport {
+    fn start(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            let (kill_tx, kill_rx) = oneshot::channel();
+            let output = self.output.clone();
+            // The runner logs under the supervisor's `tunnel` span.
+            let (handle, ready_rx) = match &self.runner {
+                Runner::Process(inv) => {
+                    let inv = inv.clone();
+                    let handle =
+                        tokio::spawn(async move { run_ssh_process(&inv, output, kill_rx).await }.in_current_span());
+                    (handle, None)
+                }
+                Runner::Pty(inv, password) => {
+                    let inv = inv.clone();
+                    let password = password.clone();
+                    let handle = tokio::spawn(
+                        async move { run_ssh_with_pty(&inv, Some(&password), output, kill_rx).await }.in_current_span(),
+                    );
+                    (handle, None)
+                }
+                #[cfg(feature = "native-ssh")]
+                Runner::Native(rule) => {
+                    let rule = rule.as_ref().clone();
+                    let (ready_tx, ready_rx) = oneshot::channel();
+                    let handle = tokio::spawn(
+                        async move { run_native(&rule, output, ready_tx, kill_rx).await }.in_current_span(),
+                    );
+                    (handle, Some(ready_rx))
+                }
+            };
+            self.running = Some(RunnerTask {
+                handle,
+                kill_tx: Some(kill_tx),
+                started: Instant::now(),
+                ready_rx,
+            });
+            Ok(())
+        })
+    }
+
+    fn ready(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            while let Some(task) = &mut self.running {
+                if task.handle.is_finished() {
+                    break;
+                }
+                match &self.readiness {
+                    // A connection also makes a -L forward open a channel to the
+                    // remote side once; ssh keeps running either way.
+                    Readiness::Listening(host, port) => {
+                        if let Ok(Ok(_)) = timeout(READY_INTERVAL, TcpStream::connect((host.as_str(), *port))).await {
+                            return Ok(());
+                        }
+                    }
+                    Readiness::Settled(after) => {
+                        if task.started.elapsed() >= *after {
+                            return Ok(());
+                        }
+                    }
+                    #[cfg(feature = "native-ssh")]
+                    Readiness::Signalled => {
+                        let Some(rx) = &mut task.ready_rx else {
+                            return Ok(());
+                        };
+                        match timeout(READY_INTERVAL, rx).await {
+                            Ok(Ok(())) => {
+                                task.ready_rx = None;
+                                return Ok(());
+                            }
+                            // The runner gave up before setting up the forward.
+                            Ok(Err(_)) => break,
+                            Err(_) => continue,
+                        }
+                    }
+                }
+                sleep(READY_INTERVAL).await;
+            }
+            Err(io::Error::other("session ended before it was ready"))
+        })
+    }
+
+    fn wait(&mut self) -> BoxFuture<'_, ExitReason> {
+        Box::pin(async move {
+            let Some(task) = &mut self.running else {
+                return ExitReason::Error("not started".to_string());
+            };
+            let res = (&mut task.handle).await;
+            let killed = task.kill_tx.is_none();
+            self.running = None;
+            match res {
+                // double result: runner task exit ok, runner exit ok
+                Ok(Ok(_)) if killed => ExitReason::Killed,
+                Ok(Ok(exit)) if exit.auth_failed => ExitReason::AuthFailed,
+                Ok(Ok(exit)) => ExitReason::Exited { code: exit.code },
+                Ok(Err(e)) => ExitReason::Error(format!("ssh runner error: {}", e)),
+                Err(e) => ExitReason::Error(format!("ssh runner task join error: {}", e)),
+            }
+        })
+    }
+
+    fn kill(&mut self) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            if let Some(task) = &mut self.running {
+                if let Some(kill_tx) = task.kill_tx.take() {
+                    let _ = kill_tx.send(());
+                }
+                let _ = self.wait().await;
+            }
+        })
+    }
+
+    fn record_output(&mut self, output: Arc<RecentOutput>) {
+        self.output = Some(output);
+    }
+}
@@ -0,0 +1,310 @@
+use std::collections::HashMap;
+use std::io;
+use std::time::SystemTime;
+
+use serde::Serialize;
+use tokio::sync::{broadcast, watch};
+use tokio::task::JoinSet;
+use tokio::time::{interval, sleep, timeout, Duration, Instant};
+use tracing::{error, info, warn};
+
+use crate::audit::format_timestamp;
+use crate::config::{WebhookConfig, WebhookEvent};
+use crate::http;
+use crate::supervisor::{Event, EventKind};
+
+// How often rules are checked against `down_after`.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// A session must stay connected (i.e. ready, not just started) this long to end
+// an outage, as for the supervisor's backoff reset, so a flapping rule still
+// counts as down.
+const STABLE_AFTER: Duration = Duration::from_secs(5);
+// Delay before the first retry; doubled for each further one, up to MAX_RETRY_DELAY.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+// What is POSTed when no `template` is configured.
+#[derive(Debug, Clone, Serialize)]
+struct Notification {
+    rule: String,
+    event: WebhookEvent,
+    // Last exit reason (empty for `up`).
+    reason: String,
+    // How long the rule has been (or was) down.
+    down_secs: u64,
+    timestamp: String,
+}
+
+// Where a rule is between connected and down.
+#[derive(Default)]
+struct Outage {
+    // First disconnect not followed by a stable session.
+    since: Option<Instant>,
+    reason: String,
+    // Start of the current session.
+    connected: Option<Instant>,
+    // `down` was sent for this outage.
+    reported: bool,
+}
+
+/// Report the state changes of the rules in `events` to `config.url` until
+/// shutdown, then wait for the requests still being sent.
+pub async fn run(
+    config: WebhookConfig,
+    mut events: broadcast::Receiver<Event>,
+    mut shutdown: watch::Receiver<bool>,
+) -> io::Result<()> {
+    if !config.url.starts_with("http://") && !config.url.starts_with("https://") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("webhook url must start with http:// or https://: {}", config.url),
+        ));
+    }
+    let mut webhook = Webhook {
+        target: target(&config.url),
+        config,
+        rules: HashMap::new(),
+        sending: JoinSet::new(),
+    };
+    info!("Sending webhooks to {}", webhook.target);
+    let mut check = interval(CHECK_INTERVAL);
+    loop {
+        tokio::select! {
+            // Events sent before shutdown (e.g. the auth failure that ended the last rule) go first.
+            biased;
+            res = events.recv() => match res {
+                Ok(event) => webhook.event(event),
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("webhook {}: {} lifecycle event(s) dropped", webhook.target, n)
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            _ = shutdown.changed() => break,
+            _ = check.tick() => webhook.check(),
+            Some(_) = webhook.sending.join_next() => {}
+        }
+    }
+    while webhook.sending.join_next().await.is_some() {}
+    Ok(())
+}
+
+struct Webhook {
+    config: WebhookConfig,
+    // Scheme and host of the URL; the rest may hold a secret token.
+    target: String,
+    rules: HashMap<String, Outage>,
+    sending: JoinSet<()>,
+}
+
+impl Webhook {
+    fn event(&mut self, event: Event) {
+        if !self.config.rules.is_empty() && !self.config.rules.contains(&event.rule) {
+            return;
+        }
+        let outage = self.rules.entry(event.rule.clone()).or_default();
+        match event.kind {
+            EventKind::Connected => outage.connected = Some(Instant::now()),
+            EventKind::Disconnected { reason } => {
+                outage.connected = None;
+                outage.since.get_or_insert_with(Instant::now);
+                outage.reason = reason;
+            }
+            EventKind::AuthFailed => {
+                // Not retried: reported now, and no `down` to follow.
+                self.rules.remove(&event.rule);
+                self.notify(&event.rule, WebhookEvent::AuthFailed, "authentication failed", Duration::ZERO);
+            }
+            // Stopped on purpose (status API, on-demand idle, shutdown).
+            EventKind::Stopped => {
+                self.rules.remove(&event.rule);
+            }
+            EventKind::RuleStarting | EventKind::BackoffScheduled { .. } => {}
+        }
+    }
+
+    fn check(&mut self) {
+        let mut due = Vec::new();
+        for (rule, outage) in &mut self.rules {
+            let Some(since) = outage.since else {
+                continue;
+            };
+            if outage.connected.is_some_and(|c| c.elapsed() >= STABLE_AFTER) {
+                if outage.reported {
+                    due.push((rule.clone(), WebhookEvent::Up, String::new(), since.elapsed()));
+                }
+                outage.since = None;
+                outage.reported = false;
+            } else if !outage.reported && since.elapsed() >= Duration::from_secs(self.config.down_after) {
+                due.push((rule.clone(), WebhookEvent::Down, outage.reason.clone(), since.elapsed()));
+                outage.reported = true;
+            }
+        }
+        for (rule, event, reason, down) in due {
+            self.notify(&rule, event, &reason, down);
+        }
+    }
+
+    fn notify(&mut self, rule: &str, event: WebhookEvent, reason: &str, down: Duration) {
+        if !self.config.events.contains(&event) {
+            return;
+        }
+        let notification = Notification {
+            rule: rule.to_string(),
+            event,
+            reason: reason.to_string(),
+            down_secs: down.as_secs(),
+            timestamp: format_timestamp(SystemTime::now()),
+        };
+        let body = match &self.config.template {
+            Some(template) => render(template, &notification),
+            None => match serde_json::to_string(&notification) {
+                Ok(body) => body,
+                Err(e) => {
+                    warn!("webhook {}: {}", self.target, e);
+                    return;
+                }
+            },
+        };
+        let (url, target) = (self.config.url.clone(), self.target.clone());
+        let headers: Vec<(String, String)> = self.config.headers.clone().into_iter().collect();
+        let (retries, limit) = (self.config.retries, Duration::from_secs(self.config.timeout));
+        self.sending.spawn(async move {
+            let what = format!("{} for {}", event_name(notification.event), notification.rule);
+            for attempt in 0..=retries {
+                if attempt > 0 {
+                    sleep(retry_delay(attempt)).await;
+                }
+                match timeout(limit, http::post(&url, &headers, &body)).await {
+                    Ok(Ok(code)) if (200..300).contains(&code) => {
+                        info!(rule = %notification.rule, "Webhook {} sent to {}", what, target);
+                        return;
+                    }
+                    Ok(Ok(code)) => warn!(attempt, "Webhook {} to {}: HTTP {}", what, target, code),
+                    Ok(Err(e)) => warn!(attempt, "Webhook {} to {}: {}", what, target, e),
+                    Err(_) => warn!(attempt, "Webhook {} to {}: timed out after {:?}", what, target, limit),
+                }
+            }
+            error!(rule = %notification.rule, "Giving up on webhook {} to {} after {} attempt(s)", what, target, retries + 1);
+        });
+    }
+}
+
+// Fill in the placeholders; values are JSON-escaped, as templates are usually
+// JSON with the placeholders inside strings.
+fn render(template: &str, notification: &Notification) -> String {
+    let escape = |s: &str| {
+        let quoted = serde_json::to_string(s).unwrap_or_default();
+        quoted[1..quoted.len() - 1].to_string()
+    };
+    template
+        .replace("{rule}", &escape(&notification.rule))
+        .replace("{event}", event_name(notification.event))
+        .replace("{reason}", &escape(&notification.reason))
+        .replace("{down_secs}", &notification.down_secs.to_string())
+        .replace("{timestamp}", &notification.timestamp)
+}
+
+// Delay before retry number `attempt` (from 1).
+fn retry_delay(attempt: u32) -> Duration {
+    RETRY_DELAY
+        .checked_mul(2u32.saturating_pow(attempt - 1))
+        .map_or(MAX_RETRY_DELAY, |d| d.min(MAX_RETRY_DELAY))
+}
+
+fn event_name(event: WebhookEvent) -> &'static str {
+    match event {
+        WebhookEvent::Down => "down",
+        WebhookEvent::Up => "up",
+        WebhookEvent::AuthFailed => "auth_failed",
+    }
+}
+
+// "https://hooks.example.com/services/T0/B0/secret" -> "https://hooks.example.com"
+fn target(url: &str) -> String {
+    let (scheme, rest) = url.split_once("://").unwrap_or(("", url));
+    let host = rest.split(['/', '?']).next().unwrap_or_default();
+    let host = host.rsplit('@').next().unwrap_or_default();
+    format!("{}://{}", scheme, host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::StatusBoard;
+    use crate::supervisor::{supervise_subject, Subject};
+    use crate::transport::{BoxFuture, ExitReason, Transport};
+
+    // ssh to a blackholed host: started, never ready, gives up after ConnectTimeout.
+    struct Blackholed;
+
+    impl Transport for Blackholed {
+        fn start(&mut self) -> BoxFuture<'_, io::Result<()>> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn ready(&mut self) -> BoxFuture<'_, io::Result<()>> {
+            Box::pin(async {
+                sleep(Duration::from_secs(10)).await;
+                Err(io::Error::other("connect timed out"))
+            })
+        }
+
+        fn wait(&mut self) -> BoxFuture<'_, ExitReason> {
+            Box::pin(async { ExitReason::Exited { code: 255 } })
+        }
+
+        fn kill(&mut self) -> BoxFuture<'_, ()> {
+            Box::pin(async {})
+        }
+    }
+
+    #[test]
+    fn retry_delays_double_up_to_a_minute() {
+        let delays: Vec<u64> = (1..=8).map(|n| retry_delay(n).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 32, 60, 60]);
+        assert_eq!(retry_delay(33), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(u32::MAX), MAX_RETRY_DELAY);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn down_is_due_for_a_transport_that_hangs_then_fails() {
+        let (events_tx, mut events_rx) = broadcast::channel(64);
+        let board = StatusBoard::new(events_tx);
+        let subject = Subject {
+            kind: "ssh forward",
+            full: "prod".to_string(),
+            short: "prod".to_string(),
+            status: Some(board.register("prod".to_string(), String::new(), false)),
+            hooks: None,
+            depends_on: Vec::new(),
+        };
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        // Nothing is posted with no events selected; the outage is still tracked.
+        let config: WebhookConfig = toml::from_str("url = \"http://127.0.0.1:9/\"\nevents = []").unwrap();
+        let down_after = Duration::from_secs(config.down_after);
+        let mut webhook = Webhook {
+            target: target(&config.url),
+            config,
+            rules: HashMap::new(),
+            sending: JoinSet::new(),
+        };
+
+        let mut transport = Blackholed;
+        let observer = async {
+            // Down from the first failure, ten seconds in.
+            let checks = (Duration::from_secs(10) + down_after + CHECK_INTERVAL).as_secs();
+            for _ in 0..checks {
+                sleep(CHECK_INTERVAL).await;
+                while let Ok(event) = events_rx.try_recv() {
+                    webhook.event(event);
+                }
+                webhook.check();
+            }
+            webhook.rules.get("prod").is_some_and(|o| o.reported)
+        };
+        tokio::select! {
+            _ = supervise_subject(&subject, &mut transport, shutdown_rx) => panic!("supervisor ended"),
+            reported = observer => assert!(reported, "down was not due"),
+        }
+    }
+}
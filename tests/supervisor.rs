@@ -0,0 +1,218 @@
+// Restart / backoff / auth-failure behavior of the supervisor, driven by a
+// scripted fake transport on a paused clock.
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use ssh_tunnel_manager::supervisor::supervise;
+use ssh_tunnel_manager::transport::{BoxFuture, ExitReason, Transport};
+use ssh_tunnel_manager::ForwardingRule;
+use tokio::sync::watch;
+use tokio::time::{sleep, timeout, Duration, Instant};
+
+enum Step {
+    // Session runs for this long, then ends with the reason.
+    Run(Duration, ExitReason),
+    // start() fails.
+    StartError,
+    // Session runs until killed.
+    Hang,
+    // Session ends with the reason after this long, before it was ready.
+    Unready(Duration, ExitReason),
+}
+
+#[derive(Default)]
+struct Log {
+    starts: Vec<Instant>,
+    kills: usize,
+}
+
+struct ScriptedTransport {
+    script: VecDeque<Step>,
+    current: Option<Step>,
+    log: Arc<Mutex<Log>>,
+}
+
+fn scripted(script: Vec<Step>) -> (Box<dyn Transport>, Arc<Mutex<Log>>) {
+    let log = Arc::new(Mutex::new(Log::default()));
+    let transport = ScriptedTransport {
+        script: script.into(),
+        current: None,
+        log: log.clone(),
+    };
+    (Box::new(transport), log)
+}
+
+impl Transport for ScriptedTransport {
+    fn start(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            self.log.lock().unwrap().starts.push(Instant::now());
+            // Script exhausted: behave like a rule whose credentials stopped working.
+            let step = self
+                .script
+                .pop_front()
+                .unwrap_or(Step::Run(Duration::ZERO, ExitReason::AuthFailed));
+            if let Step::StartError = step {
+                return Err(io::Error::other("scripted start error"));
+            }
+            self.current = Some(step);
+            Ok(())
+        })
+    }
+
+    fn ready(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            let Some(Step::Unready(after, reason)) = &self.current else {
+                return Ok(());
+            };
+            sleep(*after).await;
+            self.current = Some(Step::Run(Duration::ZERO, reason.clone()));
+            Err(io::Error::other("scripted session ended"))
+        })
+    }
+
+    fn wait(&mut self) -> BoxFuture<'_, ExitReason> {
+        Box::pin(async move {
+            match self.current.take() {
+                Some(Step::Run(after, reason)) => {
+                    sleep(after).await;
+                    reason
+                }
+                Some(Step::Hang) => {
+                    self.current = Some(Step::Hang);
+                    std::future::pending().await
+                }
+                _ => ExitReason::Error("not started".to_string()),
+            }
+        })
+    }
+
+    fn kill(&mut self) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            self.current = None;
+            self.log.lock().unwrap().kills += 1;
+        })
+    }
+}
+
+fn rule() -> ForwardingRule {
+    toml::from_str(
+        r#"
+local_port = 15432
+remote_address = "db.internal:5432"
+ssh_host = "bastion.example.com"
+ssh_user = "tester"
+"#,
+    )
+    .unwrap()
+}
+
+fn exited_now() -> Step {
+    Step::Run(Duration::ZERO, ExitReason::Exited { code: 1 })
+}
+
+// Delays between consecutive starts, in whole seconds.
+fn gaps(log: &Mutex<Log>) -> Vec<u64> {
+    let starts = &log.lock().unwrap().starts;
+    starts.windows(2).map(|w| (w[1] - w[0]).as_secs()).collect()
+}
+
+#[tokio::test(start_paused = true)]
+async fn auth_failure_stops_without_retry() {
+    let (transport, log) = scripted(vec![Step::Run(
+        Duration::from_secs(1),
+        ExitReason::AuthFailed,
+    )]);
+    let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    supervise(rule(), transport, shutdown_rx).await.unwrap();
+
+    assert_eq!(log.lock().unwrap().starts.len(), 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn failed_attempts_back_off_linearly_up_to_twenty_seconds() {
+    let script = (0..12).map(|_| exited_now()).collect();
+    let (transport, log) = scripted(script);
+    let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    supervise(rule(), transport, shutdown_rx).await.unwrap();
+
+    assert_eq!(gaps(&log), vec![2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 20, 20]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn long_lived_session_resets_backoff() {
+    let script = vec![
+        exited_now(),
+        exited_now(),
+        // Connected for a while before dropping: next retry is quick again.
+        Step::Run(Duration::from_secs(60), ExitReason::Exited { code: 1 }),
+        exited_now(),
+    ];
+    let (transport, log) = scripted(script);
+    let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    supervise(rule(), transport, shutdown_rx).await.unwrap();
+
+    assert_eq!(gaps(&log), vec![2, 4, 61, 2]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn sessions_that_never_came_up_keep_backing_off() {
+    // E.g. ssh timing out on an unreachable host: long-lived, but never up.
+    let script = (0..3)
+        .map(|_| Step::Unready(Duration::from_secs(10), ExitReason::Exited { code: 255 }))
+        .collect();
+    let (transport, log) = scripted(script);
+    let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    supervise(rule(), transport, shutdown_rx).await.unwrap();
+
+    assert_eq!(gaps(&log), vec![12, 14, 16]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn start_errors_are_retried_with_backoff() {
+    let script = vec![Step::StartError, Step::StartError];
+    let (transport, log) = scripted(script);
+    let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    supervise(rule(), transport, shutdown_rx).await.unwrap();
+
+    assert_eq!(gaps(&log), vec![2, 4]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn shutdown_kills_running_session() {
+    let (transport, log) = scripted(vec![Step::Hang]);
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    let task = tokio::spawn(supervise(rule(), transport, shutdown_rx));
+    sleep(Duration::from_secs(30)).await;
+    shutdown_tx.send(true).unwrap();
+    task.await.unwrap().unwrap();
+
+    let log = log.lock().unwrap();
+    assert_eq!(log.starts.len(), 1);
+    assert_eq!(log.kills, 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn shutdown_interrupts_backoff() {
+    let script = (0..10).map(|_| exited_now()).collect();
+    let (transport, log) = scripted(script);
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    let task = tokio::spawn(supervise(rule(), transport, shutdown_rx));
+    // First retry is scheduled 2s after the first exit.
+    sleep(Duration::from_secs(1)).await;
+    shutdown_tx.send(true).unwrap();
+    timeout(Duration::from_millis(10), task)
+        .await
+        .expect("supervisor waited for the backoff")
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(log.lock().unwrap().starts.len(), 1);
+}
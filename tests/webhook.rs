@@ -0,0 +1,160 @@
+// Webhook notifications, fed synthetic lifecycle events and posting to a
+// local HTTP receiver.
+use ssh_tunnel_manager::config::WebhookConfig;
+use ssh_tunnel_manager::{webhook, Event, EventKind};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout, Duration};
+
+struct Received {
+    head: String,
+    body: String,
+}
+
+// Accept POSTs, answering them with `statuses` in turn (then 200), and pass
+// each request on.
+async fn receiver(statuses: Vec<u16>) -> (String, mpsc::UnboundedReceiver<Received>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/hooks/secret-token", listener.local_addr().unwrap());
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut statuses = statuses.into_iter();
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = Vec::new();
+            let mut chunk = [0u8; 1024];
+            let (head, length) = loop {
+                let n = stream.read(&mut chunk).await.unwrap();
+                buf.extend_from_slice(&chunk[..n]);
+                if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                    let head = String::from_utf8_lossy(&buf[..end]).into_owned();
+                    let length = head
+                        .lines()
+                        .find_map(|l| l.strip_prefix("Content-Length: "))
+                        .map(|l| l.parse::<usize>().unwrap())
+                        .unwrap();
+                    buf.drain(..end + 4);
+                    break (head, length);
+                }
+            };
+            while buf.len() < length {
+                let n = stream.read(&mut chunk).await.unwrap();
+                buf.extend_from_slice(&chunk[..n]);
+            }
+            let status = statuses.next().unwrap_or(200);
+            let reply = format!("HTTP/1.1 {} X\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+            stream.write_all(reply.as_bytes()).await.unwrap();
+            let body = String::from_utf8(buf).unwrap();
+            let _ = tx.send(Received { head, body });
+        }
+    });
+    (url, rx)
+}
+
+fn webhook_config(url: &str, extra: &str) -> WebhookConfig {
+    toml::from_str(&format!("url = \"{url}\"\n{extra}")).unwrap()
+}
+
+fn event(rule: &str, kind: EventKind) -> Event {
+    Event {
+        rule: rule.to_string(),
+        kind,
+    }
+}
+
+fn start(config: WebhookConfig) -> (broadcast::Sender<Event>, watch::Sender<bool>, JoinHandle<()>) {
+    let (events, rx) = broadcast::channel(16);
+    let (shutdown, shutdown_rx) = watch::channel(false);
+    let task = tokio::spawn(async move { webhook::run(config, rx, shutdown_rx).await.unwrap() });
+    (events, shutdown, task)
+}
+
+async fn next(rx: &mut mpsc::UnboundedReceiver<Received>, within: Duration) -> Received {
+    timeout(within, rx.recv()).await.expect("no webhook request").unwrap()
+}
+
+#[tokio::test]
+async fn auth_failure_is_posted_with_the_template() {
+    let (url, mut rx) = receiver(vec![]).await;
+    let config = webhook_config(
+        &url,
+        r#"
+template = '{"text": "Tunnel {rule}: {event} ({reason})"}'
+headers = { Authorization = "Bearer abc" }
+"#,
+    );
+    let (events, _shutdown, _task) = start(config);
+
+    events.send(event("prod", EventKind::RuleStarting)).unwrap();
+    events.send(event("prod", EventKind::AuthFailed)).unwrap();
+
+    let request = next(&mut rx, Duration::from_secs(5)).await;
+    assert!(request.head.starts_with("POST /hooks/secret-token HTTP/1.1\r\n"));
+    assert!(request.head.contains("\r\nAuthorization: Bearer abc"));
+    assert!(request.head.contains("\r\nContent-Type: application/json"));
+    assert_eq!(request.body, r#"{"text": "Tunnel prod: auth_failed (authentication failed)"}"#);
+}
+
+#[tokio::test]
+async fn down_is_sent_once_the_rule_stays_down_past_the_threshold() {
+    let (url, mut rx) = receiver(vec![]).await;
+    let (events, _shutdown, _task) = start(webhook_config(&url, "down_after = 2"));
+
+    events.send(event("prod", EventKind::Connected)).unwrap();
+    events.send(event("prod", EventKind::Disconnected { reason: "exited, code=255".to_string() })).unwrap();
+    // Reconnects that do not last keep the outage going.
+    sleep(Duration::from_millis(500)).await;
+    events.send(event("prod", EventKind::Connected)).unwrap();
+    events.send(event("prod", EventKind::Disconnected { reason: "exited, code=1".to_string() })).unwrap();
+
+    assert!(timeout(Duration::from_millis(1000), rx.recv()).await.is_err(), "sent before down_after");
+    let request = next(&mut rx, Duration::from_secs(3)).await;
+    let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+    assert_eq!(body["rule"], "prod");
+    assert_eq!(body["event"], "down");
+    assert_eq!(body["reason"], "exited, code=1");
+    assert!(body["down_secs"].as_u64().unwrap() >= 2);
+
+    // Reported once per outage.
+    assert!(timeout(Duration::from_millis(1500), rx.recv()).await.is_err());
+}
+
+#[tokio::test]
+async fn failed_posts_are_retried() {
+    let (url, mut rx) = receiver(vec![500, 503]).await;
+    let (events, _shutdown, _task) = start(webhook_config(&url, "retries = 2"));
+
+    events.send(event("prod", EventKind::AuthFailed)).unwrap();
+
+    for _ in 0..3 {
+        let request = next(&mut rx, Duration::from_secs(5)).await;
+        assert!(request.body.contains(r#""event":"auth_failed""#));
+    }
+    assert!(timeout(Duration::from_millis(2500), rx.recv()).await.is_err(), "retried after success");
+}
+
+#[tokio::test]
+async fn other_rules_and_events_are_not_reported() {
+    let (url, mut rx) = receiver(vec![]).await;
+    let config = webhook_config(&url, "rules = [\"prod\"]\nevents = [\"down\"]\ndown_after = 0");
+    let (events, _shutdown, _task) = start(config);
+
+    events.send(event("staging", EventKind::Disconnected { reason: "exited, code=255".to_string() })).unwrap();
+    events.send(event("prod", EventKind::AuthFailed)).unwrap();
+
+    assert!(timeout(Duration::from_millis(2500), rx.recv()).await.is_err());
+}
+
+#[tokio::test]
+async fn pending_notifications_are_sent_before_shutdown_completes() {
+    let (url, mut rx) = receiver(vec![]).await;
+    let (events, shutdown, task) = start(webhook_config(&url, ""));
+
+    events.send(event("prod", EventKind::AuthFailed)).unwrap();
+    shutdown.send(true).unwrap();
+
+    timeout(Duration::from_secs(5), task).await.unwrap().unwrap();
+    next(&mut rx, Duration::from_millis(500)).await;
+}